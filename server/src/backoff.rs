@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+const MIN_DELAY: Duration = Duration::from_millis(5);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+// Waits longer after each accept error in a row, so running out of file descriptors doesn't turn
+// an accept loop into a busy loop
+#[derive(Default)]
+pub struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    pub fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    pub async fn wait(&mut self) {
+        self.delay = (self.delay * 2).clamp(MIN_DELAY, MAX_DELAY);
+        tokio::time::sleep(self.delay).await;
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    env, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BindMode {
+    V4,
+    V6,
+    Dual, // one IPv6 socket that also accepts IPv4 clients
+}
+
+impl BindMode {
+    pub fn from_env() -> Self {
+        match env::var("BIND_MODE").as_deref() {
+            Ok("v4") | Err(_) => BindMode::V4,
+            Ok("v6") => BindMode::V6,
+            Ok("dual") => BindMode::Dual,
+            Ok(other) => panic!("BIND_MODE must be v4, v6 or dual, not {other}"),
+        }
+    }
+
+    fn addr(self, port: u16) -> SocketAddr {
+        match self {
+            BindMode::V4 => (Ipv4Addr::UNSPECIFIED, port).into(),
+            BindMode::V6 | BindMode::Dual => (Ipv6Addr::UNSPECIFIED, port).into(),
+        }
+    }
+}
+
+fn bind(mode: BindMode, port: u16, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let addr = mode.addr(port);
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+
+    if mode != BindMode::V4 {
+        socket.set_only_v6(mode == BindMode::V6)?;
+    }
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket)
+}
+
+pub fn bind_tcp(mode: BindMode, port: u16) -> io::Result<TcpListener> {
+    let socket = bind(mode, port, Type::STREAM, Protocol::TCP)?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+pub fn bind_udp(mode: BindMode, port: u16) -> io::Result<UdpSocket> {
+    let socket = bind(mode, port, Type::DGRAM, Protocol::UDP)?;
+
+    UdpSocket::from_std(socket.into())
+}
@@ -1,207 +1,1458 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    env, fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::{error, info, trace};
-use regex::Regex;
+use async_trait::async_trait;
+use log::{error, info, trace, warn};
+use regex::{Captures, Regex};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream, tcp::OwnedWriteHalf},
-    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
+    net::{TcpListener, UnixListener},
+    sync::{
+        OwnedSemaphorePermit, Semaphore,
+        mpsc::{Receiver, Sender, channel, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    backoff::AcceptBackoff,
+    bind::{BindMode, bind_tcp},
+    line_reader::{LineError, LineReader},
+    proto_server::ProtoServer,
 };
 
-async fn handle_client(tx: UnboundedSender<Packet>, stream: TcpStream, addr: SocketAddr) {
-    let (stream, write_stream) = stream.into_split();
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+// Marks replayed lines so they aren't mistaken for live ones
+const HISTORY_PREFIX: &str = "(history) ";
+// Lines dropped in a row before a client is disconnected for flooding
+const FLOOD_STRIKES: u32 = 20;
+// Packets queued for `start_server` before clients have to wait
+const CHANNEL_CAPACITY: usize = 1024;
+// How long a client waits for room in the channel before it is disconnected
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+// Transient write errors tolerated per line before a client counts as gone
+const WRITE_RETRIES: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// Allows `rate` lines per second with bursts of up to `rate` lines
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
 
-    let _ = tx.send(Packet::NewConnection(write_stream, addr));
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+async fn handle_client(
+    tx: Sender<Packet>,
+    stream: Box<dyn ChatStream>,
+    addr: Peer,
+    id: u64,
+    idle_timeout: Duration,
+    rate_limit: Option<u32>,
+    _permit: OwnedSemaphorePermit, // released when the client task ends
+) {
+    let (stream, write_stream) = tokio::io::split(stream);
+    // `start_server` drops the sender along with the user, turned away or otherwise
+    let (closer, mut closed) = oneshot::channel();
+
+    let _ = tx
+        .send(Packet::NewConnection(write_stream, closer, addr, id))
+        .await;
 
     let _guard = ConnectionGuard {
         addr,
+        id,
         tx: tx.clone(),
     };
 
-    let mut reader = BufReader::new(stream);
-    let mut line = String::new();
+    let mut bucket = rate_limit.map(TokenBucket::new);
+    let mut strikes = 0;
+
+    let mut reader = LineReader::new(stream, MAX_LINE_LENGTH);
     loop {
-        line.clear();
-        match reader.read_line(&mut line).await {
-            Ok(n) => {
-                if n == 0 {
-                    info!("Connection closed ip={addr}");
-                    break;
-                }
+        let next = tokio::select! {
+            _ = &mut closed => {
+                info!("Closing connection id={id} ip={addr}");
+                break;
+            }
+            next = tokio::time::timeout(idle_timeout, reader.next_line()) => next,
+        };
+        let Ok(read) = next else {
+            info!("Client was idle for {idle_timeout:?} id={id} ip={addr}");
+            let _ = tx
+                .send(Packet::Reject(
+                    addr,
+                    id,
+                    "* disconnected due to inactivity\n",
+                ))
+                .await;
+            break;
+        };
+
+        let line = match read {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                info!("Connection closed id={id} ip={addr}");
+                break;
+            }
+            Err(LineError::TooLong(max_length)) => {
+                warn!("Client sent a line longer than {max_length} bytes id={id} ip={addr}");
+                let _ = tx
+                    .send(Packet::Reject(addr, id, "* Message too long\n"))
+                    .await;
+                break;
             }
             Err(e) => {
-                error!("Could not read from stream: {e} ip={addr}");
+                error!("Could not read from stream: {e} id={id} ip={addr}");
                 break;
             }
         };
 
-        // Remove the \n or \r from end
-        line.truncate(line.trim_end().len());
+        if let Some(bucket) = &mut bucket {
+            // Only drops in a row count, so bursts hours apart don't add up to a disconnect
+            if bucket.try_take() {
+                strikes = 0;
+            } else {
+                strikes += 1;
+                if strikes == 1 {
+                    warn!("Client is over the rate limit, dropping lines id={id} ip={addr}");
+                    let _ = tx
+                        .send(Packet::Notice(
+                            id,
+                            "* slow down, your messages are being dropped\n",
+                        ))
+                        .await;
+                }
+                if strikes >= FLOOD_STRIKES {
+                    warn!("Client kept flooding, disconnecting id={id} ip={addr}");
+                    let _ = tx
+                        .send(Packet::Reject(addr, id, "* disconnected for flooding\n"))
+                        .await;
+                    break;
+                }
+                continue;
+            }
+        }
 
-        if let Err(e) = tx.send(Packet::NewMessage(addr, line.clone())) {
-            error!("Could not write to channel: {e} ip={addr}");
-            break;
+        // Waits for room in the channel, but a server this backed up is better off without us
+        match tokio::time::timeout(SEND_TIMEOUT, tx.send(Packet::NewMessage(addr, id, line))).await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Could not write to channel: {e} id={id} ip={addr}");
+                break;
+            }
+            Err(_) => {
+                warn!(
+                    "Server stayed backed up for {SEND_TIMEOUT:?}, disconnecting id={id} ip={addr}"
+                );
+                break;
+            }
         }
     }
 }
 
-async fn start_server(mut rx: UnboundedReceiver<Packet>) {
+pub struct ChatConfig {
+    pub max_users: Option<usize>, // named users allowed at once, `None` is unlimited
+    pub banner: Option<String>,   // sent to every new connection after the username prompt
+    pub idle_timeout: Duration,   // connections that send nothing for this long are dropped
+    pub max_connections: usize,   // open sockets, named or not, before new ones are turned away
+    pub timestamps: bool,         // prefix broadcasts with the time they were sent
+    pub status_port: Option<u16>, // plain-text HTTP status page, `None` disables it
+    pub rate_limit: Option<u32>,  // lines per second per connection, `None` is unlimited
+    pub max_username_length: usize, // longer names are refused like invalid ones
+    pub history: usize,           // chat lines replayed to users as they join, 0 disables it
+    pub banned_words: Option<PathBuf>, // words to redact from relayed lines, one per line
+    pub unix_socket: Option<PathBuf>, // listen on this Unix domain socket instead of TCP
+    pub messages: ChatMessages,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            max_users: None,
+            banner: None,
+            idle_timeout: Duration::from_secs(5 * 60),
+            max_connections: 1024,
+            timestamps: false,
+            status_port: None,
+            rate_limit: Some(20),
+            max_username_length: 16,
+            history: 0,
+            banned_words: None,
+            unix_socket: None,
+            messages: ChatMessages::default(),
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        let max_users = env::var("CHAT_MAX_USERS")
+            .ok()
+            .map_or(defaults.max_users, |v| {
+                Some(v.parse().expect("CHAT_MAX_USERS must be a number"))
+            });
+        let banner = env::var("CHAT_BANNER").ok().or(defaults.banner);
+        let idle_timeout = env::var("CHAT_IDLE_TIMEOUT")
+            .ok()
+            .map_or(defaults.idle_timeout, |v| {
+                Duration::from_secs(
+                    v.parse()
+                        .expect("CHAT_IDLE_TIMEOUT must be a number of seconds"),
+                )
+            });
+        let max_connections = env::var("CHAT_MAX_CONNECTIONS")
+            .ok()
+            .map_or(defaults.max_connections, |v| {
+                v.parse().expect("CHAT_MAX_CONNECTIONS must be a number")
+            });
+        let timestamps =
+            env::var("CHAT_TIMESTAMPS").map_or(defaults.timestamps, |v| v == "1" || v == "true");
+        let status_port = env::var("CHAT_STATUS_PORT")
+            .ok()
+            .map_or(defaults.status_port, |v| {
+                Some(v.parse().expect("CHAT_STATUS_PORT must be a port number"))
+            });
+
+        let rate_limit = env::var("CHAT_RATE_LIMIT")
+            .ok()
+            .map_or(defaults.rate_limit, |v| {
+                Some(v.parse().expect("CHAT_RATE_LIMIT must be a number"))
+            })
+            .filter(|&rate| rate > 0);
+
+        let max_username_length =
+            env::var("CHAT_MAX_USERNAME_LENGTH")
+                .ok()
+                .map_or(defaults.max_username_length, |v| {
+                    v.parse()
+                        .expect("CHAT_MAX_USERNAME_LENGTH must be a number")
+                });
+
+        let history = env::var("CHAT_HISTORY").ok().map_or(defaults.history, |v| {
+            v.parse().expect("CHAT_HISTORY must be a number")
+        });
+
+        let banned_words = env::var("CHAT_BANNED_WORDS")
+            .ok()
+            .map(PathBuf::from)
+            .or(defaults.banned_words);
+
+        let unix_socket = env::var("CHAT_UNIX_SOCKET")
+            .ok()
+            .map(PathBuf::from)
+            .or(defaults.unix_socket);
+
+        Self {
+            max_users,
+            banner,
+            idle_timeout,
+            max_connections,
+            timestamps,
+            status_port,
+            rate_limit,
+            max_username_length,
+            history,
+            banned_words,
+            unix_socket,
+            messages: ChatMessages::from_env(),
+        }
+    }
+}
+
+// What the server says around joining, the defaults are what the protocol expects word for word
+pub struct ChatMessages {
+    pub prompt: String,           // sent as soon as a client connects
+    pub invalid_username: String, // sent before dropping a client with a bad or taken name
+    pub room_contains: String,    // followed by the names of everyone already in the room
+    pub room_empty: String,       // sent instead when nobody else is there
+}
+
+impl Default for ChatMessages {
+    fn default() -> Self {
+        Self {
+            prompt: String::from("Please enter your username..."),
+            invalid_username: String::from("Invalid username..."),
+            room_contains: String::from("* The room contains: "),
+            room_empty: String::from("* The room is currently empty"),
+        }
+    }
+}
+
+impl ChatMessages {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            prompt: env::var("CHAT_PROMPT").unwrap_or(defaults.prompt),
+            invalid_username: env::var("CHAT_INVALID_USERNAME")
+                .unwrap_or(defaults.invalid_username),
+            room_contains: env::var("CHAT_ROOM_CONTAINS").unwrap_or(defaults.room_contains),
+            room_empty: env::var("CHAT_ROOM_EMPTY").unwrap_or(defaults.room_empty),
+        }
+    }
+}
+
+async fn start_server(config: ChatConfig, filter: Option<Regex>, mut rx: Receiver<Packet>) {
     info!("Started the chat server");
+    // Keyed by the id assigned at accept time, which is unique for the whole run
     let mut users = HashMap::new();
+    let mut relayed: u64 = 0;
+    let mut history: VecDeque<String> = VecDeque::with_capacity(config.history);
     while let Some(message) = rx.recv().await {
         match message {
-            Packet::NewConnection(mut stream, addr) => {
-                info!("Received new connection ip={addr}");
-                let _ = stream.write_all(b"Please enter your username...\n").await;
+            Packet::NewConnection(mut stream, closer, addr, id) => {
+                info!("Received new connection id={id} ip={addr}");
+                let prompt = format!("{}\n", config.messages.prompt);
+                let _ = stream.write_all(prompt.as_bytes()).await;
+                if let Some(banner) = &config.banner {
+                    let _ = stream.write_all(format!("* {}\n", banner).as_bytes()).await;
+                }
+                let _ = stream.flush().await;
                 users.insert(
-                    addr,
+                    id,
                     User {
                         stream,
+                        _closer: closer,
+                        addr,
                         username: String::new(),
+                        ignored: HashSet::new(),
+                        echo: false,
                     },
                 );
             }
-            Packet::NewMessage(addr, message) => {
-                let mut disconnected = Vec::new();
+            Packet::NewMessage(addr, id, line) => {
+                // A failed write may have dropped the client while its reader was still going
+                let Some(sender) = users.get(&id) else {
+                    continue;
+                };
 
                 let (sender_username, just_joined) = {
-                    let needs_username = users
-                        .get(&addr)
-                        .map(|u| u.username.is_empty())
-                        .unwrap_or(false);
-
-                    if needs_username {
-                        let trimmed = message.trim();
-                        let is_invalid = !is_valid_username(trimmed)
-                            || users.values().any(|u| u.username == trimmed);
+                    if sender.username.is_empty() {
+                        // Taken as sent, so " alice", "alice " and "\talice" are refused rather than
+                        // becoming "alice"
+                        let name = line.as_str();
+                        let is_invalid = !is_valid_username(name)
+                            || name.len() > config.max_username_length
+                            || users
+                                .values()
+                                .any(|u| u.username.eq_ignore_ascii_case(name));
 
                         if is_invalid {
-                            let sender = users.get_mut(&addr).unwrap();
-                            let _ = sender.stream.write_all(b"Invalid username...\n").await;
+                            let sender = users.get_mut(&id).unwrap();
+                            let invalid = format!("{}\n", config.messages.invalid_username);
+                            let _ = sender.stream.write_all(invalid.as_bytes()).await;
                             let _ = sender.stream.shutdown().await;
+                            // Dropping the user stops its reader, so a second try never gets here
+                            users.remove(&id);
                             continue;
                         }
 
-                        let usernames = users
-                            .values()
-                            .filter(|u| !u.username.is_empty())
-                            .map(|u| u.username.as_str())
-                            .collect::<Vec<_>>()
-                            .join(", ");
+                        if let Some(max_users) = config.max_users {
+                            let named = users.values().filter(|u| !u.username.is_empty()).count();
+                            if named >= max_users {
+                                let sender = users.get_mut(&id).unwrap();
+                                let _ = sender.stream.write_all(b"* room is full\n").await;
+                                let _ = sender.stream.shutdown().await;
+                                users.remove(&id);
+                                continue;
+                            }
+                        }
 
-                        let sender = users.get_mut(&addr).unwrap();
+                        let usernames = room_usernames(&users);
+
+                        let sender = users.get_mut(&id).unwrap();
 
                         if !usernames.is_empty() {
                             let _ = sender
                                 .stream
                                 .write_all(
-                                    format!("* The room contains: {}\n", usernames).as_bytes(),
+                                    format!("{}{}\n", config.messages.room_contains, usernames)
+                                        .as_bytes(),
                                 )
                                 .await;
                         } else {
                             let _ = sender
                                 .stream
-                                .write_all(b"* The room is currently empty\n")
+                                .write_all(format!("{}\n", config.messages.room_empty).as_bytes())
                                 .await;
                         }
 
-                        trace!("User set their username ip={addr} username={trimmed}",);
-                        sender.username = trimmed.to_string();
-                        (sender.username.as_str(), true)
+                        for line in &history {
+                            let _ = sender
+                                .stream
+                                .write_all(format!("{HISTORY_PREFIX}{line}").as_bytes())
+                                .await;
+                        }
+                        let _ = sender.stream.flush().await;
+
+                        trace!("User set their username id={id} ip={addr} username={name}");
+                        sender.username = name.to_string();
+                        (sender.username.clone(), true)
                     } else {
-                        let sender = users.get_mut(&addr).unwrap();
-                        (sender.username.as_str(), false)
+                        (sender.username.clone(), false)
                     }
                 };
+                let message = strip_control_chars(&line);
+                let message = message.trim_end();
 
-                let message = if just_joined {
-                    format!("* {} has entered the room\n", sender_username)
-                } else {
-                    trace!("User sent new message ip={addr} message={message}");
-                    format!("[{}] {}\n", sender_username, message)
-                };
+                if !just_joined && message == "/users" {
+                    let usernames = room_usernames(&users);
+                    let sender = users.get_mut(&id).unwrap();
+                    let _ = sender
+                        .stream
+                        .write_all(
+                            format!("{}{}\n", config.messages.room_contains, usernames).as_bytes(),
+                        )
+                        .await;
+                    let _ = sender.stream.flush().await;
+                    continue;
+                }
 
-                for (target_addr, u) in users.iter_mut() {
-                    if target_addr != &addr && !u.username.is_empty() {
-                        if let Err(e) = u.stream.write_all(message.as_bytes()).await {
-                            error!("Could not write to stream: {e} ip={addr}");
-                            disconnected.push(*target_addr);
+                // The first line always sets the username, so there is one by the time this is sent
+                if !just_joined && message == "/whoami" {
+                    let sender = users.get_mut(&id).unwrap();
+                    let _ = sender
+                        .stream
+                        .write_all(format!("* You are {}\n", sender_username).as_bytes())
+                        .await;
+                    let _ = sender.stream.flush().await;
+                    continue;
+                }
+
+                if !just_joined && let Some(rest) = message.strip_prefix("/msg ") {
+                    let reply = match rest.split_once(' ') {
+                        Some((target, text)) => {
+                            let line = format!("[pm from {}] {}\n", sender_username, text);
+                            // Usernames are unique regardless of case, so the target is found the same way
+                            let target_id = users
+                                .iter()
+                                .find(|(_, u)| {
+                                    !u.username.is_empty()
+                                        && u.username.eq_ignore_ascii_case(target)
+                                })
+                                .map(|(target_id, _)| *target_id);
+
+                            match target_id {
+                                Some(target_id) => {
+                                    let u = users.get_mut(&target_id).unwrap();
+                                    if let Err(e) =
+                                        write_retrying(u, target_id, line.as_bytes()).await
+                                    {
+                                        error!(
+                                            "Could not write to stream: {e} id={target_id} ip={}",
+                                            u.addr
+                                        );
+                                        let departed =
+                                            users.remove(&target_id).into_iter().collect();
+                                        announce_departures(
+                                            &mut users,
+                                            departed,
+                                            config.timestamps,
+                                        )
+                                        .await;
+                                    } else {
+                                        relayed += 1;
+                                    }
+                                    None
+                                }
+                                None => Some(format!("* No such user: {}\n", target)),
+                            }
                         }
+                        None => Some(String::from("* Usage: /msg <username> <text>\n")),
+                    };
+
+                    if let Some(reply) = reply
+                        && let Some(sender) = users.get_mut(&id)
+                    {
+                        let _ = sender.stream.write_all(reply.as_bytes()).await;
+                        let _ = sender.stream.flush().await;
                     }
+                    continue;
                 }
 
-                for addr in disconnected {
-                    users.remove(&addr);
+                if !just_joined
+                    && let Some((command, target)) = message.split_once(' ')
+                    && (command == "/ignore" || command == "/unignore")
+                {
+                    let exists = users
+                        .values()
+                        .any(|u| !u.username.is_empty() && u.username.eq_ignore_ascii_case(target));
+                    let sender = users.get_mut(&id).unwrap();
+                    // Usernames are unique regardless of case, so ignores are too
+                    let ignored = target.to_ascii_lowercase();
+
+                    let reply = if command == "/unignore" {
+                        if sender.ignored.remove(&ignored) {
+                            format!("* No longer ignoring {}\n", target)
+                        } else {
+                            format!("* You are not ignoring {}\n", target)
+                        }
+                    } else if target.eq_ignore_ascii_case(&sender_username) {
+                        String::from("* You can't ignore yourself\n")
+                    } else if exists {
+                        sender.ignored.insert(ignored);
+                        format!("* Ignoring {}\n", target)
+                    } else {
+                        format!("* No such user: {}\n", target)
+                    };
+
+                    let _ = sender.stream.write_all(reply.as_bytes()).await;
+                    let _ = sender.stream.flush().await;
+                    continue;
                 }
-            }
-            Packet::RemoveConnection(addr) => {
-                info!("Client disconnected ip={addr}");
-                let user = users.remove(&addr).unwrap();
-                if !user.username.is_empty() {
-                    for (_, u) in users.iter_mut().filter(|(_, u)| !u.username.is_empty()) {
-                        let _ = u
-                            .stream
-                            .write_all(
-                                format!("* {} has left the room\n", user.username).as_bytes(),
-                            )
-                            .await;
+
+                if !just_joined && let Some(setting) = message.strip_prefix("/echo ") {
+                    let sender = users.get_mut(&id).unwrap();
+                    let reply = match setting {
+                        "on" => {
+                            sender.echo = true;
+                            "* Echo is on\n"
+                        }
+                        "off" => {
+                            sender.echo = false;
+                            "* Echo is off\n"
+                        }
+                        _ => "* Usage: /echo on|off\n",
+                    };
+
+                    let _ = sender.stream.write_all(reply.as_bytes()).await;
+                    let _ = sender.stream.flush().await;
+                    continue;
+                }
+
+                let message = match &filter {
+                    Some(filter) if !just_joined => filter
+                        .replace_all(message, |caps: &Captures| {
+                            "*".repeat(caps[0].chars().count())
+                        }),
+                    _ => Cow::Borrowed(message),
+                };
+
+                let is_chat_line = !just_joined && !message.starts_with("/me ");
+
+                // Actions are echoed back so the sender sees them the way everyone else does
+                let (message, except, from) = if just_joined {
+                    let online = users.values().filter(|u| !u.username.is_empty()).count();
+                    let message = format!(
+                        "* {} has entered the room ({} online)\n",
+                        users[&id].username, online
+                    );
+                    (message, Some(id), None)
+                } else if let Some(action) = message.strip_prefix("/me ") {
+                    trace!("User sent an action id={id} ip={addr} action={action}");
+                    (
+                        format!("* {} {}\n", sender_username, action),
+                        None,
+                        Some(sender_username.as_str()),
+                    )
+                } else {
+                    trace!("User sent new message id={id} ip={addr} message={message}");
+                    (
+                        format!("[{}] {}\n", sender_username, message),
+                        (!users[&id].echo).then_some(id),
+                        Some(sender_username.as_str()),
+                    )
+                };
+                let message = if config.timestamps {
+                    format!("{}{}", timestamp_prefix(), message)
+                } else {
+                    message
+                };
+
+                let departed = broadcast(&mut users, &message, except, from).await;
+                announce_departures(&mut users, departed, config.timestamps).await;
+                if is_chat_line && config.history > 0 {
+                    if history.len() == config.history {
+                        history.pop_front();
                     }
+                    history.push_back(message);
+                }
+                if !just_joined {
+                    relayed += 1;
+                }
+            }
+            Packet::Status(reply) => {
+                let named = users.values().filter(|u| !u.username.is_empty()).count();
+                let _ = reply.send(format!(
+                    "connected {}\nnamed {}\nrelayed {}\n",
+                    users.len(),
+                    named,
+                    relayed
+                ));
+            }
+            Packet::Reject(addr, id, message) => {
+                info!("Rejecting client id={id} ip={addr}");
+                if let Some(user) = users.get_mut(&id) {
+                    let _ = user.stream.write_all(message.as_bytes()).await;
+                    let _ = user.stream.shutdown().await;
                 }
             }
+            Packet::Notice(id, message) => {
+                if let Some(user) = users.get_mut(&id) {
+                    let _ = user.stream.write_all(message.as_bytes()).await;
+                    let _ = user.stream.flush().await;
+                }
+            }
+            Packet::Shutdown => {
+                for u in users.values_mut() {
+                    let _ = u.stream.write_all(b"* server is shutting down\n").await;
+                    let _ = u.stream.shutdown().await;
+                }
+                break;
+            }
+            Packet::RemoveConnection(addr, id) => {
+                info!("Client disconnected id={id} ip={addr}");
+                // A failed broadcast may already have dropped this client
+                let Some(user) = users.remove(&id) else {
+                    continue;
+                };
+                announce_departures(&mut users, vec![user], config.timestamps).await;
+            }
         }
     }
 }
 
 enum Packet {
-    NewConnection(OwnedWriteHalf, SocketAddr),
-    NewMessage(SocketAddr, String),
-    RemoveConnection(SocketAddr),
+    NewConnection(ChatWriter, oneshot::Sender<()>, Peer, u64),
+    NewMessage(Peer, u64, String),
+    Reject(Peer, u64, &'static str),
+    Notice(u64, &'static str),
+    RemoveConnection(Peer, u64),
+    Status(oneshot::Sender<String>),
+    Shutdown,
+}
+
+// Both kinds of listener hand out streams that can be split and written to the same way
+trait ChatStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ChatStream for S {}
+
+type ChatWriter = WriteHalf<Box<dyn ChatStream>>;
+
+// Where a client connected from, Unix socket clients are usually unnamed
+#[derive(Clone, Copy)]
+enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Box<dyn ChatStream>, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Unix))
+            }
+        }
+    }
 }
 
 struct User {
-    stream: OwnedWriteHalf,
+    stream: ChatWriter,
+    _closer: oneshot::Sender<()>, // dropped along with the user, which stops its reader
+    addr: Peer,
     username: String,
+    ignored: HashSet<String>, // lowercased usernames whose messages aren't delivered
+    echo: bool,               // whether the user's own chat lines are sent back to them
 }
 
 struct ConnectionGuard {
-    addr: SocketAddr,
-    tx: UnboundedSender<Packet>,
+    addr: Peer,
+    id: u64,
+    tx: Sender<Packet>,
 }
 
 impl Drop for ConnectionGuard {
     fn drop(&mut self) {
-        let _ = self.tx.send(Packet::RemoveConnection(self.addr));
+        // Drop can't wait for room in the channel, so a full one gets the packet from a task
+        if let Err(TrySendError::Full(packet)) = self
+            .tx
+            .try_send(Packet::RemoveConnection(self.addr, self.id))
+        {
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                let _ = tx.send(packet).await;
+            });
+        }
+    }
+}
+
+// Sends the message to every named user but `except` and those ignoring `from`, dropping and
+// returning the ones that can't be written to
+async fn broadcast(
+    users: &mut HashMap<u64, User>,
+    message: &str,
+    except: Option<u64>,
+    from: Option<&str>,
+) -> Vec<User> {
+    let mut disconnected = Vec::new();
+    let from = from.map(str::to_ascii_lowercase);
+
+    for (target_id, u) in users.iter_mut() {
+        if Some(*target_id) != except
+            && !u.username.is_empty()
+            && !from.as_ref().is_some_and(|from| u.ignored.contains(from))
+            && let Err(e) = write_retrying(u, *target_id, message.as_bytes()).await
+        {
+            error!(
+                "Could not write to stream: {e} id={target_id} ip={}",
+                u.addr
+            );
+            disconnected.push(*target_id);
+        }
+    }
+
+    disconnected
+        .into_iter()
+        .filter_map(|id| users.remove(&id))
+        .collect()
+}
+
+// Tells the room each named user has left, and in turn anyone dropped while being told
+async fn announce_departures(
+    users: &mut HashMap<u64, User>,
+    mut departed: Vec<User>,
+    timestamps: bool,
+) {
+    while let Some(user) = departed.pop() {
+        if user.username.is_empty() {
+            continue;
+        }
+        let mut message = format!("* {} has left the room\n", user.username);
+        if timestamps {
+            message.insert_str(0, &timestamp_prefix());
+        }
+        departed.extend(broadcast(users, &message, None, None).await);
+    }
+}
+
+// Like `write_all` followed by `flush`, but a WouldBlock or Interrupted only fails the write once
+// it keeps happening
+async fn write_retrying(user: &mut User, id: u64, mut buf: &[u8]) -> io::Result<()> {
+    let mut retries = 0;
+
+    while !buf.is_empty() {
+        match user.stream.write(buf).await {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) && retries < WRITE_RETRIES =>
+            {
+                retries += 1;
+                warn!(
+                    "Retrying write after transient error: {e} id={id} ip={}",
+                    user.addr
+                );
+                tokio::time::sleep(WRITE_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
     }
+
+    user.stream.flush().await
+}
+
+fn room_usernames(users: &HashMap<u64, User>) -> String {
+    users
+        .values()
+        .filter(|u| !u.username.is_empty())
+        .map(|u| u.username.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Keeps clients from ringing bells or sending escape sequences to everyone else's terminal
+fn strip_control_chars(message: &str) -> String {
+    message.chars().filter(|c| !c.is_ascii_control()).collect()
+}
+
+// `[HH:MM:SS] ` in UTC, there is no timezone database to get the local time from
+fn timestamp_prefix() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (hours, minutes, seconds) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    format!("[{hours:02}:{minutes:02}:{seconds:02}] ")
 }
 
 fn is_valid_username(username: &str) -> bool {
     // Compile the regex only once
     lazy_static::lazy_static! {
-        static ref USERNAME_RE: Regex = Regex::new(r"^[a-zA-Z0-9]*$").unwrap();
+        static ref USERNAME_RE: Regex = Regex::new(r"^[a-zA-Z0-9]+$").unwrap();
     }
 
     USERNAME_RE.is_match(username)
 }
 
-pub async fn run_chat() {
-    let listener = TcpListener::bind("0.0.0.0:8080")
-        .await
-        .unwrap_or_else(|e| panic!("Could not bind listener: {e}"));
+// Answers every connection with the server's counters as a plain-text HTTP response
+async fn run_status(listener: TcpListener, tx: Sender<Packet>) {
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept status connection: {e}");
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            // Only the request line matters, and it isn't even looked at
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+
+            let (reply, status) = oneshot::channel();
+            let _ = tx.send(Packet::Status(reply)).await;
+            let Ok(body) = status.await else {
+                return;
+            };
+            trace!("Serving status ip={addr}");
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+pub struct ChatServer;
+
+#[async_trait]
+impl ProtoServer for ChatServer {
+    fn name(&self) -> &str {
+        "chat"
+    }
+
+    fn description(&self) -> &str {
+        "Budget Chat, a line-based chat room"
+    }
+
+    async fn run(&self, port: u16) {
+        let config = ChatConfig::from_env();
+        let server = match config.unix_socket.clone() {
+            Some(path) => {
+                let listener = UnixListener::bind(&path)
+                    .unwrap_or_else(|e| panic!("Could not bind {}: {e}", path.display()));
+                run_chat_unix(config, listener)
+            }
+            None => {
+                let listener = bind_tcp(BindMode::from_env(), port)
+                    .unwrap_or_else(|e| panic!("Could not bind listener: {e}"));
+                run_chat(config, listener).map(|(_, server)| server)
+            }
+        }
+        .unwrap_or_else(|e| panic!("Could not start the chat server: {e}"));
+        let _ = server.await;
+    }
+}
+
+// Takes an already bound listener, so it can be bound to any address, port 0 included.
+// Returns the address it ended up on and the task serving it, which finishes on Ctrl-C.
+pub fn run_chat(
+    config: ChatConfig,
+    listener: TcpListener,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let local_addr = listener.local_addr()?;
+    info!("🚀 Server listening on {local_addr}");
+
+    Ok((local_addr, spawn_server(config, Listener::Tcp(listener))?))
+}
+
+// Like `run_chat`, for a listener bound to a Unix domain socket
+pub fn run_chat_unix(
+    config: ChatConfig,
+    listener: UnixListener,
+) -> std::io::Result<JoinHandle<()>> {
+    let local_addr = listener.local_addr()?;
+    info!("🚀 Server listening on {local_addr:?}");
+
+    spawn_server(config, Listener::Unix(listener))
+}
+
+fn spawn_server(config: ChatConfig, listener: Listener) -> std::io::Result<JoinHandle<()>> {
+    let filter = match &config.banned_words {
+        Some(path) => load_word_filter(path)?,
+        None => None,
+    };
+
+    // Bound up front like the main listener, so a taken port is an error rather than a panic
+    let status = match config.status_port {
+        Some(port) => {
+            let listener = bind_tcp(BindMode::from_env(), port)?;
+            info!("Status page listening on {}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    Ok(tokio::spawn(serve(config, filter, listener, status)))
+}
+
+// Matches any word listed in the file, one per line, as a whole word regardless of case
+fn load_word_filter(path: &Path) -> std::io::Result<Option<Regex>> {
+    let words: Vec<String> = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
 
-    info!("🚀 Server listening on :8080");
+    Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
 
-    let (tx, rx) = unbounded_channel::<Packet>();
+async fn serve(
+    config: ChatConfig,
+    filter: Option<Regex>,
+    listener: Listener,
+    status: Option<TcpListener>,
+) {
+    let (tx, rx) = channel::<Packet>(CHANNEL_CAPACITY);
 
-    tokio::spawn(start_server(rx));
+    let idle_timeout = config.idle_timeout;
+    let rate_limit = config.rate_limit;
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let server = tokio::spawn(start_server(config, filter, rx));
+    let status = status.map(|listener| tokio::spawn(run_status(listener, tx.clone())));
 
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    let mut next_id: u64 = 0;
+    let mut backoff = AcceptBackoff::default();
     loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                tokio::spawn(handle_client(tx.clone(), stream, addr));
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
             }
-            Err(e) => {
-                error!("Could not accept connection: {e}");
+            accepted = listener.accept() => match accepted {
+                Ok((mut stream, addr)) => {
+                    backoff.reset();
+                    let id = next_id;
+                    next_id += 1;
+                    let Ok(permit) = connections.clone().try_acquire_owned() else {
+                        warn!("Too many connections, turning away id={id} ip={addr}");
+                        tokio::spawn(async move {
+                            let _ = stream.write_all(b"* server full\n").await;
+                            let _ = stream.shutdown().await;
+                        });
+                        continue;
+                    };
+                    tokio::spawn(handle_client(tx.clone(), stream, addr, id, idle_timeout, rate_limit, permit));
+                }
+                Err(e) => {
+                    error!("Could not accept connection: {e}");
+                    backoff.wait().await;
+                }
             }
         }
     }
+
+    // New connections are refused from here on. Shutdown queues behind everything clients have
+    // already sent, so those messages are still delivered before the users are disconnected.
+    drop(listener);
+    let _ = tx.send(Packet::Shutdown).await;
+    let _ = server.await;
+    if let Some(status) = status {
+        status.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::{
+            TcpStream,
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+        },
+    };
+
+    use super::*;
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // Serves `config` on an ephemeral loopback port for the rest of the test
+    async fn start(config: ChatConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (addr, _) = run_chat(config, listener).unwrap();
+        addr
+    }
+
+    struct Client {
+        read: BufReader<OwnedReadHalf>,
+        write: OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut client = Self {
+                read: BufReader::new(read),
+                write,
+            };
+            assert_eq!(client.recv().await, "Please enter your username...");
+            client
+        }
+
+        // Connects and picks a name, returning the line the server answered it with
+        async fn join(addr: SocketAddr, name: &str) -> (Self, String) {
+            let mut client = Self::connect(addr).await;
+            client.send(name).await;
+            let reply = client.recv().await;
+            (client, reply)
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.write
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        // The next line without its `\n`
+        async fn recv(&mut self) -> String {
+            let mut line = String::new();
+            let n = tokio::time::timeout(RECV_TIMEOUT, self.read.read_line(&mut line))
+                .await
+                .expect("timed out waiting for a line")
+                .unwrap();
+            assert_ne!(n, 0, "connection closed while waiting for a line");
+            line.truncate(line.trim_end_matches('\n').len());
+            line
+        }
+
+        async fn assert_closed(&mut self) {
+            let mut line = String::new();
+            let n = tokio::time::timeout(RECV_TIMEOUT, self.read.read_line(&mut line))
+                .await
+                .expect("timed out waiting for the connection to close")
+                .unwrap();
+            assert_eq!(n, 0, "expected the connection to be closed, got {line:?}");
+        }
+    }
+
+    #[test]
+    fn usernames_need_at_least_one_alphanumeric_character() {
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username(" "));
+        assert!(!is_valid_username("   "));
+        assert!(is_valid_username("a"));
+        assert!(is_valid_username("7"));
+    }
+
+    #[tokio::test]
+    async fn relays_joins_and_messages_between_two_clients() {
+        let addr = start(ChatConfig::default()).await;
+
+        let (mut a, roster) = Client::join(addr, "A").await;
+        assert_eq!(roster, "* The room is currently empty");
+        let (mut b, roster) = Client::join(addr, "B").await;
+        assert_eq!(roster, "* The room contains: A");
+        assert!(a.recv().await.starts_with("* B has entered the room"));
+
+        a.send("hello").await;
+        assert_eq!(b.recv().await, "[A] hello");
+    }
+
+    #[tokio::test]
+    async fn survives_clients_that_leave_before_naming_themselves() {
+        let addr = start(ChatConfig::default()).await;
+        for _ in 0..10 {
+            drop(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let (_, roster) = Client::join(addr, "alice").await;
+        assert_eq!(roster, "* The room is currently empty");
+    }
+
+    #[tokio::test]
+    async fn simultaneous_joins_see_each_other_exactly_once() {
+        let addr = start(ChatConfig::default()).await;
+        let mut a = Client::connect(addr).await;
+        let mut b = Client::connect(addr).await;
+        a.send("alice").await;
+        b.send("bob").await;
+
+        // Whoever got in first saw an empty room and then hears of the other, the other finds
+        // them in its roster and hears nothing of their join
+        let (a_roster, b_roster) = (a.recv().await, b.recv().await);
+        let (mut first, mut second, second_name) = if a_roster == "* The room is currently empty" {
+            assert_eq!(b_roster, "* The room contains: alice");
+            (a, b, "bob")
+        } else {
+            assert_eq!(a_roster, "* The room contains: bob");
+            assert_eq!(b_roster, "* The room is currently empty");
+            (b, a, "alice")
+        };
+        assert!(
+            first
+                .recv()
+                .await
+                .starts_with(&format!("* {second_name} has entered the room"))
+        );
+
+        first.send("hi").await;
+        assert!(second.recv().await.ends_with("] hi"));
+    }
+
+    #[tokio::test]
+    async fn turns_away_users_past_the_room_cap() {
+        let addr = start(ChatConfig {
+            max_users: Some(1),
+            ..ChatConfig::default()
+        })
+        .await;
+
+        let (_alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, reply) = Client::join(addr, "bob").await;
+        assert_eq!(reply, "* room is full");
+        bob.assert_closed().await;
+    }
+
+    #[tokio::test]
+    async fn usernames_are_unique_regardless_of_case() {
+        let addr = start(ChatConfig::default()).await;
+
+        let (_alice, _) = Client::join(addr, "alice").await;
+        let (mut shouting, reply) = Client::join(addr, "ALICE").await;
+        assert_eq!(reply, "Invalid username...");
+        shouting.assert_closed().await;
+    }
+
+    #[tokio::test]
+    async fn strips_control_characters_from_relayed_lines() {
+        let addr = start(ChatConfig::default()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+
+        bob.send("ding\x07 dong\x1b[2J").await;
+        assert_eq!(alice.recv().await, "[bob] ding dong[2J");
+    }
+
+    #[tokio::test]
+    async fn ignored_users_lines_are_not_delivered() {
+        let addr = start(ChatConfig::default()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+        let (mut carol, _) = Client::join(addr, "carol").await;
+        alice.recv().await;
+        bob.recv().await;
+
+        alice.send("/ignore BOB").await;
+        assert_eq!(alice.recv().await, "* Ignoring BOB");
+
+        bob.send("hi").await;
+        assert_eq!(carol.recv().await, "[bob] hi");
+        carol.send("hey").await;
+        assert_eq!(alice.recv().await, "[carol] hey");
+
+        alice.send("/unignore bob").await;
+        assert_eq!(alice.recv().await, "* No longer ignoring bob");
+        bob.send("again").await;
+        assert_eq!(alice.recv().await, "[bob] again");
+    }
+
+    #[tokio::test]
+    async fn shutdown_delivers_everything_queued_before_it() {
+        let (tx, rx) = channel(CHANNEL_CAPACITY);
+        let server = tokio::spawn(start_server(ChatConfig::default(), None, rx));
+
+        let (alice, mut alice_client) = tokio::io::duplex(64 * 1024);
+        let (_, alice) = tokio::io::split(Box::new(alice) as Box<dyn ChatStream>);
+        let (bob, _bob_client) = tokio::io::duplex(64 * 1024);
+        let (_, bob) = tokio::io::split(Box::new(bob) as Box<dyn ChatStream>);
+
+        let packets = [
+            Packet::NewConnection(alice, oneshot::channel().0, Peer::Unix, 0),
+            Packet::NewMessage(Peer::Unix, 0, String::from("alice")),
+            Packet::NewConnection(bob, oneshot::channel().0, Peer::Unix, 1),
+            Packet::NewMessage(Peer::Unix, 1, String::from("bob")),
+            Packet::NewMessage(Peer::Unix, 1, String::from("one")),
+            Packet::NewMessage(Peer::Unix, 1, String::from("two")),
+            Packet::NewMessage(Peer::Unix, 1, String::from("three")),
+            Packet::Shutdown,
+        ];
+        for packet in packets {
+            assert!(tx.send(packet).await.is_ok());
+        }
+        tokio::time::timeout(RECV_TIMEOUT, server)
+            .await
+            .expect("start_server did not stop")
+            .unwrap();
+
+        let mut received = String::new();
+        alice_client.read_to_string(&mut received).await.unwrap();
+        assert!(
+            received.ends_with("[bob] one\n[bob] two\n[bob] three\n* server is shutting down\n"),
+            "{received:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_dropped_by_a_failed_write_are_announced_and_their_lines_ignored() {
+        let (tx, rx) = channel(CHANNEL_CAPACITY);
+        let server = tokio::spawn(start_server(ChatConfig::default(), None, rx));
+
+        let (alice, mut alice_client) = tokio::io::duplex(64 * 1024);
+        let (_, alice) = tokio::io::split(Box::new(alice) as Box<dyn ChatStream>);
+        let (bob, bob_client) = tokio::io::duplex(64 * 1024);
+        let (_, bob) = tokio::io::split(Box::new(bob) as Box<dyn ChatStream>);
+
+        for packet in [
+            Packet::NewConnection(alice, oneshot::channel().0, Peer::Unix, 0),
+            Packet::NewMessage(Peer::Unix, 0, String::from("alice")),
+            Packet::NewConnection(bob, oneshot::channel().0, Peer::Unix, 1),
+            Packet::NewMessage(Peer::Unix, 1, String::from("bob")),
+        ] {
+            assert!(tx.send(packet).await.is_ok());
+        }
+        // Writes to bob fail from here on, while its reader could still be sending lines
+        drop(bob_client);
+        for packet in [
+            Packet::NewMessage(Peer::Unix, 0, String::from("hi")),
+            Packet::NewMessage(Peer::Unix, 1, String::from("still here")),
+            Packet::NewMessage(Peer::Unix, 0, String::from("/users")),
+            Packet::Shutdown,
+        ] {
+            assert!(tx.send(packet).await.is_ok());
+        }
+        tokio::time::timeout(RECV_TIMEOUT, server)
+            .await
+            .expect("start_server did not stop")
+            .unwrap();
+
+        let mut received = String::new();
+        alice_client.read_to_string(&mut received).await.unwrap();
+        assert!(
+            received.ends_with(
+                "* bob has left the room\n* The room contains: alice\n* server is shutting down\n"
+            ),
+            "{received:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_taken_status_port_is_an_error() {
+        let taken = bind_tcp(BindMode::from_env(), 0).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let config = ChatConfig {
+            status_port: Some(taken.local_addr().unwrap().port()),
+            ..ChatConfig::default()
+        };
+        let Err(e) = run_chat(config, listener) else {
+            panic!("started with the status port already taken");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn usernames_are_checked_before_control_characters_are_stripped() {
+        let addr = start(ChatConfig::default()).await;
+
+        for name in ["\talice", "ali\x07ce", " alice", "alice "] {
+            let (mut client, reply) = Client::join(addr, name).await;
+            assert_eq!(reply, "Invalid username...", "{name:?}");
+            client.assert_closed().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn private_messages_find_their_target_regardless_of_case() {
+        let addr = start(ChatConfig::default()).await;
+        let (mut alice, _) = Client::join(addr, "alice").await;
+        let (mut bob, _) = Client::join(addr, "bob").await;
+        alice.recv().await;
+
+        bob.send("/msg ALICE hi").await;
+        assert_eq!(alice.recv().await, "[pm from bob] hi");
+        bob.send("/msg carol hi").await;
+        assert_eq!(bob.recv().await, "* No such user: carol");
+    }
+
+    // The server's end of an in-memory connection and the client's end to read it from
+    fn duplex_writer() -> (ChatWriter, tokio::io::DuplexStream) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let (_, write) = tokio::io::split(Box::new(server) as Box<dyn ChatStream>);
+        (write, client)
+    }
+
+    #[tokio::test]
+    async fn turned_away_users_are_forgotten_and_cannot_try_again() {
+        let (tx, rx) = channel(CHANNEL_CAPACITY);
+        let config = ChatConfig {
+            max_users: Some(1),
+            ..ChatConfig::default()
+        };
+        let server = tokio::spawn(start_server(config, None, rx));
+
+        let (alice, _alice_client) = duplex_writer();
+        let (bob, _bob_client) = duplex_writer();
+        let (bob_closer, bob_closed) = oneshot::channel();
+        let (erin, _erin_client) = duplex_writer();
+        let (erin_closer, erin_closed) = oneshot::channel();
+        let (dave, mut dave_client) = duplex_writer();
+
+        for packet in [
+            Packet::NewConnection(alice, oneshot::channel().0, Peer::Unix, 0),
+            Packet::NewMessage(Peer::Unix, 0, String::from("alice")),
+            Packet::NewConnection(bob, bob_closer, Peer::Unix, 1),
+            Packet::NewMessage(Peer::Unix, 1, String::from("bob")),
+            Packet::NewConnection(erin, erin_closer, Peer::Unix, 2),
+            Packet::NewMessage(Peer::Unix, 2, String::from("!!!")),
+            // Lines their readers had already sent, with alice gone so the room has space
+            Packet::RemoveConnection(Peer::Unix, 0),
+            Packet::NewMessage(Peer::Unix, 1, String::from("bob")),
+            Packet::NewMessage(Peer::Unix, 2, String::from("erin")),
+            Packet::NewConnection(dave, oneshot::channel().0, Peer::Unix, 3),
+            Packet::NewMessage(Peer::Unix, 3, String::from("dave")),
+            Packet::Shutdown,
+        ] {
+            assert!(tx.send(packet).await.is_ok());
+        }
+        tokio::time::timeout(RECV_TIMEOUT, server)
+            .await
+            .expect("start_server did not stop")
+            .unwrap();
+
+        // Dropped senders, which is what stops the readers
+        assert!(bob_closed.await.is_err());
+        assert!(erin_closed.await.is_err());
+
+        let mut received = String::new();
+        dave_client.read_to_string(&mut received).await.unwrap();
+        assert!(
+            received.contains("* The room is currently empty\n"),
+            "{received:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_client_stops_reading_once_the_server_is_done_with_it() {
+        let (tx, mut rx) = channel(CHANNEL_CAPACITY);
+        let (stream, _client) = tokio::io::duplex(64 * 1024);
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        let reader = tokio::spawn(handle_client(
+            tx,
+            Box::new(stream),
+            Peer::Unix,
+            0,
+            Duration::from_secs(60),
+            None,
+            permit,
+        ));
+
+        let Some(Packet::NewConnection(_, closer, ..)) = rx.recv().await else {
+            panic!("handle_client did not register the connection");
+        };
+        drop(closer);
+        tokio::time::timeout(RECV_TIMEOUT, reader)
+            .await
+            .expect("handle_client kept reading")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_line_that_gets_through_forgives_earlier_drops() {
+        let (tx, mut rx) = channel(CHANNEL_CAPACITY);
+        let (stream, mut client) = tokio::io::duplex(64 * 1024);
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        tokio::spawn(handle_client(
+            tx,
+            Box::new(stream),
+            Peer::Unix,
+            0,
+            Duration::from_secs(60),
+            Some(1),
+            permit,
+        ));
+        // Held on to, dropping it would stop the reader
+        let Some(Packet::NewConnection(_, _closer, ..)) = rx.recv().await else {
+            panic!("handle_client did not register the connection");
+        };
+
+        // One line a second, so the second of each pair is dropped
+        for kept in ["a", "b"] {
+            client
+                .write_all(format!("{kept}\nx\n").as_bytes())
+                .await
+                .unwrap();
+            let next = tokio::time::timeout(RECV_TIMEOUT, rx.recv()).await;
+            assert!(matches!(next, Ok(Some(Packet::NewMessage(_, _, line))) if line == kept));
+            // Warned again after the line that got through, rather than only at the first drop
+            let next = tokio::time::timeout(RECV_TIMEOUT, rx.recv()).await;
+            assert!(matches!(next, Ok(Some(Packet::Notice(..)))));
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+        }
+    }
 }
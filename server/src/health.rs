@@ -0,0 +1,35 @@
+use std::io;
+
+use log::{error, info, trace};
+use tokio::{io::AsyncWriteExt, net::TcpListener, task::JoinHandle};
+
+use crate::backoff::AcceptBackoff;
+
+// Answers every connection with `OK` and hangs up, for liveness probes that shouldn't touch the
+// real protocol port
+pub fn spawn_health_check(listener: TcpListener) -> io::Result<JoinHandle<()>> {
+    info!("Health check listening on {}", listener.local_addr()?);
+
+    Ok(tokio::spawn(run_health_check(listener)))
+}
+
+async fn run_health_check(listener: TcpListener) {
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept health check connection: {e}");
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+
+        tokio::spawn(async move {
+            trace!("Serving health check ip={addr}");
+            let _ = stream.write_all(b"OK\n").await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
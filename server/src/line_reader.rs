@@ -0,0 +1,112 @@
+use std::fmt;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+// Reads newline-delimited UTF-8 lines, refusing any line longer than `max_length` bytes.
+// A bare `\r` ends a line too, so `hello\rworld\n` is two lines rather than one that would
+// overwrite itself on a terminal.
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    max_length: usize,
+    after_cr: bool, // the last line ended with `\r`, a `\n` right after it belongs to that ending
+}
+
+#[derive(Debug)]
+pub enum LineError {
+    TooLong(usize),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineError::TooLong(max_length) => write!(f, "line longer than {max_length} bytes"),
+            LineError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R, max_length: usize) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            max_length,
+            after_cr: false,
+        }
+    }
+
+    // Returns the next line without its `\n`, `\r\n` or `\r` ending, or `None` once the stream is
+    // closed. A last line the stream closes on before any ending is still returned.
+    pub async fn next_line(&mut self) -> Result<Option<String>, LineError> {
+        let mut line = Vec::new();
+        loop {
+            let buf = self.reader.fill_buf().await.map_err(LineError::Io)?;
+            if buf.is_empty() {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+
+            if std::mem::take(&mut self.after_cr) && buf[0] == b'\n' {
+                self.reader.consume(1);
+                continue;
+            }
+
+            match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(end) => {
+                    line.extend_from_slice(&buf[..end]);
+                    self.after_cr = buf[end] == b'\r';
+                    self.reader.consume(end + 1);
+                    break;
+                }
+                None => {
+                    let len = buf.len();
+                    line.extend_from_slice(buf);
+                    self.reader.consume(len);
+                }
+            }
+
+            // Checked as the line grows, so an endless one is cut off before it fills memory
+            if line.len() > self.max_length {
+                return Err(LineError::TooLong(self.max_length));
+            }
+        }
+
+        if line.len() > self.max_length {
+            return Err(LineError::TooLong(self.max_length));
+        }
+
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|e| LineError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn lines(reader: impl AsyncRead + Unpin) -> Vec<String> {
+        let mut reader = LineReader::new(reader, 64);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn a_bare_carriage_return_ends_a_line() {
+        assert_eq!(lines(&b"hello\rworld\n"[..]).await, ["hello", "world"]);
+    }
+
+    #[tokio::test]
+    async fn crlf_is_a_single_ending() {
+        assert_eq!(lines(&b"a\r\nb\n"[..]).await, ["a", "b"]);
+        // Even when the `\n` only arrives in the next read
+        assert_eq!(lines((&b"a\r"[..]).chain(&b"\nb\n"[..])).await, ["a", "b"]);
+    }
+}
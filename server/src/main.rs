@@ -1,17 +1,36 @@
 use std::env;
 
-use chat::run_chat;
-use speed::run_speed;
-use unusual::run_unusual;
+use bind::{BindMode, bind_tcp};
+use chat::ChatServer;
+use env_logger::Env;
+use health::spawn_health_check;
+use proto_server::ProtoServer;
+use speed::SpeedServer;
+use unusual::UnusualServer;
 
+mod backoff;
+mod bind;
 mod chat;
+mod health;
+mod line_reader;
+mod proto_server;
 mod speed;
 mod unusual;
 
+const DEFAULT_PORT: u16 = 8080;
+
+// Every runnable solution, looked up by name from the first argument
+fn servers() -> Vec<Box<dyn ProtoServer>> {
+    vec![
+        Box::new(ChatServer),
+        Box::new(UnusualServer),
+        Box::new(SpeedServer),
+    ]
+}
+
 #[tokio::main]
 async fn main() {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
         .format_target(false)
         .format_timestamp(None)
         .init();
@@ -23,12 +42,44 @@ async fn main() {
         None => String::from("chat"),
     };
 
-    match command.as_str() {
-        "chat" => run_chat().await,
-        "unusual" => run_unusual().await,
-        "speed" => run_speed().await,
-        _ => {
-            panic!("Invalid server implementation specified: {command}");
+    let port = match args.next() {
+        Some(port) => port.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid port specified: {port}");
+            std::process::exit(1);
+        }),
+        None => DEFAULT_PORT,
+    };
+
+    let servers = servers();
+
+    if matches!(command.as_str(), "list" | "--help" | "-h") {
+        println!(
+            "Usage: tcp [server] [port], defaults to chat on {DEFAULT_PORT}. Available servers:"
+        );
+        for server in &servers {
+            println!("  {:<10} {}", server.name(), server.description());
         }
+        return;
+    }
+
+    let Some(server) = servers.iter().find(|server| server.name() == command) else {
+        let names: Vec<&str> = servers.iter().map(|server| server.name()).collect();
+        eprintln!(
+            "Invalid server implementation specified: {command}, expected one of: {}",
+            names.join(", ")
+        );
+        std::process::exit(1);
     };
+
+    // Runs next to whichever server was picked, `HEALTH_PORT` unset leaves it off
+    if let Ok(health_port) = env::var("HEALTH_PORT") {
+        let health_port: u16 = health_port
+            .parse()
+            .expect("HEALTH_PORT must be a port number");
+        bind_tcp(BindMode::from_env(), health_port)
+            .and_then(spawn_health_check)
+            .unwrap_or_else(|e| panic!("Could not bind health check listener: {e}"));
+    }
+
+    server.run(port).await;
 }
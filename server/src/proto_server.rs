@@ -0,0 +1,12 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait ProtoServer: Send + Sync {
+    fn name(&self) -> &str;
+
+    // One line shown next to the name by `list`
+    fn description(&self) -> &str;
+
+    // Serves on `port` until Ctrl-C
+    async fn run(&self, port: u16);
+}
@@ -1,23 +1,94 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use log::{error, info};
-use server_macros::Packet;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use server_macros::{Packet, Serialize};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf},
     net::{TcpListener, TcpStream, tcp::OwnedWriteHalf},
     sync::{
         Mutex,
         mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        oneshot,
     },
+    task::JoinHandle,
+};
+
+use crate::{
+    backoff::AcceptBackoff,
+    bind::{BindMode, bind_tcp},
+    proto_server::ProtoServer,
 };
 
+// Bytes of a packet kept for the log when it fails to decode
+const HEX_DUMP_LEN: usize = 64;
+
+pub struct SpeedConfig {
+    pub tolerance: f64, // mph over the limit that is still let through
+    pub identify_timeout: Option<Duration>, // clients that don't say what they are in time are dropped
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.0,
+            identify_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl SpeedConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        let tolerance = env::var("SPEED_TOLERANCE")
+            .ok()
+            .map_or(defaults.tolerance, |v| {
+                v.parse()
+                    .expect("SPEED_TOLERANCE must be a number of miles per hour")
+            });
+
+        // Dispatchers legitimately sit silent waiting for tickets, so this only covers identification
+        let identify_timeout =
+            env::var("SPEED_IDENTIFY_TIMEOUT")
+                .ok()
+                .map_or(defaults.identify_timeout, |v| {
+                    let secs = v
+                        .parse()
+                        .expect("SPEED_IDENTIFY_TIMEOUT must be a number of seconds");
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                });
+
+        Self {
+            tolerance,
+            identify_timeout,
+        }
+    }
+}
+
+trait Serialize {
+    // Only the fields, for composing into larger packets
+    fn serialize_body(&self) -> Vec<u8>;
+
+    // The opcode followed by the body, ready to be written to the socket
+    fn serialize(&self) -> Vec<u8>;
+}
+
 trait Packet: Sized + Send + Sync {
     const OPCODE: u8;
 
     async fn deserialize<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, std::io::Error>;
 }
 
-#[derive(Debug, Packet)]
+#[derive(Debug, Serialize)]
 #[opcode = 0x10]
 struct ErrorPacket {
     message: String,
@@ -30,7 +101,7 @@ struct PlatePacket {
     timestamp: u32,
 }
 
-#[derive(Debug, Packet)]
+#[derive(Debug, Serialize)]
 #[opcode = 0x21]
 struct TicketPacket {
     plate: String,
@@ -48,11 +119,11 @@ struct WantHeartBeatPacket {
     interval: u32, // in deciseconds
 }
 
-#[derive(Debug, Packet)]
+#[derive(Debug, Serialize)]
 #[opcode = 0x41]
 struct HeartBeatPacket {}
 
-#[derive(Debug, Packet)]
+#[derive(Debug, Packet, Serialize)]
 #[opcode = 0x80]
 struct Camera {
     road: u16,
@@ -60,136 +131,882 @@ struct Camera {
     limit: u16, // miles per hour
 }
 
-#[derive(Debug, Packet)]
+#[derive(Debug, Packet, Serialize)]
 #[opcode = 0x81]
 struct Dispatcher {
+    #[count_for(roads)]
     numroads: u8,
-    roads: Vec<u16>, // miles per hour
+    roads: Vec<u16>, // road numbers, `numroads` of them with no length prefix of their own
+}
+
+// The server only ever decodes these, building them is for tests acting as clients
+#[cfg(test)]
+impl Camera {
+    fn new(road: u16, mile: u16, limit: u16) -> Self {
+        Self { road, mile, limit }
+    }
+}
+
+#[cfg(test)]
+impl Dispatcher {
+    fn new(roads: Vec<u16>) -> Self {
+        let numroads = u8::try_from(roads.len()).expect("A dispatcher can cover at most 255 roads");
+
+        Self { numroads, roads }
+    }
+}
+
+#[derive(Debug, Packet)]
+enum ClientPacket {
+    #[opcode = 0x20]
+    Plate(PlatePacket),
+    #[opcode = 0x40]
+    WantHeartBeat(WantHeartBeatPacket),
+    #[opcode = 0x80]
+    IAmCamera(Camera),
+    #[opcode = 0x81]
+    IAmDispatcher(Dispatcher),
 }
 
 enum MessageType {
-    ClientConnected(OwnedWriteHalf, SocketAddr),
-    ClientDisconnected(SocketAddr),
-    Plate(SocketAddr, PlatePacket),
-    WantHeartBeat(SocketAddr, WantHeartBeatPacket),
-    IAmCamera(SocketAddr, Camera),
-    IAmDispatcher(SocketAddr, Dispatcher),
+    ClientConnected(
+        Arc<Mutex<OwnedWriteHalf>>,
+        oneshot::Sender<()>,
+        SocketAddr,
+        u64,
+    ),
+    ClientDisconnected(SocketAddr, u64),
+    Plate(SocketAddr, u64, PlatePacket),
+    WantHeartBeat(SocketAddr, u64, WantHeartBeatPacket),
+    IAmCamera(SocketAddr, u64, Camera),
+    IAmDispatcher(SocketAddr, u64, Dispatcher),
+    Shutdown,
 }
 
-async fn handle_client(tx: UnboundedSender<MessageType>, stream: TcpStream, addr: SocketAddr) {
-    let (mut read, write) = stream.into_split();
+async fn handle_client(
+    tx: UnboundedSender<MessageType>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    id: u64,
+    identify_timeout: Option<Duration>,
+) {
+    let (read, write) = stream.into_split();
+    // Buffered so the small reads every field does aren't a syscall each
+    let mut read = Recorder::new(BufReader::new(read));
+    let write = Arc::new(Mutex::new(write));
+    // `run_server` drops the sender once it is done with the client, rejected or otherwise
+    let (closer, mut closed) = oneshot::channel();
 
-    _ = tx.send(MessageType::ClientConnected(write, addr));
+    _ = tx.send(MessageType::ClientConnected(
+        write.clone(),
+        closer,
+        addr,
+        id,
+    ));
 
+    let mut identified = false;
     loop {
-        let Ok(n) = read.read_u8().await else {
-            error!("Could not read from connection {addr}");
-            _ = tx.send(MessageType::ClientDisconnected(addr));
+        read.bytes.clear();
+        let next = async {
+            tokio::select! {
+                _ = &mut closed => None,
+                packet = ClientPacket::deserialize(&mut read) => Some(packet),
+            }
+        };
+        let packet = match identify_timeout.filter(|_| !identified) {
+            Some(timeout) => {
+                let Ok(packet) = tokio::time::timeout(timeout, next).await else {
+                    info!("Client did not identify within {timeout:?} id={id} ip={addr}");
+                    _ = tx.send(MessageType::ClientDisconnected(addr, id));
+                    break;
+                };
+                packet
+            }
+            None => next.await,
+        };
+        let Some(packet) = packet else {
+            info!("Closing connection id={id} ip={addr}");
             break;
         };
 
-        match n {
-            PlatePacket::OPCODE => match PlatePacket::deserialize(&mut read).await {
-                Ok(packet) => {
-                    let _ = tx.send(MessageType::Plate(addr, packet));
+        let message = match packet {
+            Ok(ClientPacket::Plate(packet)) => MessageType::Plate(addr, id, packet),
+            Ok(ClientPacket::WantHeartBeat(packet)) => MessageType::WantHeartBeat(addr, id, packet),
+            Ok(ClientPacket::IAmCamera(packet)) => {
+                identified = true;
+                MessageType::IAmCamera(addr, id, packet)
+            }
+            Ok(ClientPacket::IAmDispatcher(packet)) => {
+                identified = true;
+                MessageType::IAmDispatcher(addr, id, packet)
+            }
+            // EOF in the middle of a packet is reported as InvalidData, so this is a clean close
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                info!("Connection closed id={id} ip={addr}");
+                _ = tx.send(MessageType::ClientDisconnected(addr, id));
+                break;
+            }
+            Err(e) => {
+                error!(
+                    "Could not read packet: {e} bytes=[{}] id={id} ip={addr}",
+                    hex_dump(&read.bytes)
+                );
+                // Unknown opcodes, malformed fields and truncated packets are illegal messages,
+                // the client gets told why before the connection is dropped
+                if e.kind() == std::io::ErrorKind::InvalidData {
+                    send_error(&write, "illegal msg").await;
+                }
+                _ = tx.send(MessageType::ClientDisconnected(addr, id));
+                break;
+            }
+        };
+
+        let _ = tx.send(message);
+    }
+}
+
+// Keeps the start of the packet being decoded, so a failure can show what was received
+struct Recorder<R> {
+    inner: R,
+    bytes: Vec<u8>,
+}
+
+impl<R> Recorder<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: Vec::with_capacity(HEX_DUMP_LEN),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[start..];
+            let room = HEX_DUMP_LEN.saturating_sub(this.bytes.len());
+            this.bytes.extend_from_slice(&read[..read.len().min(room)]);
+        }
+        result
+    }
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Default)]
+struct Server {
+    cameras: HashMap<SocketAddr, Camera>,
+    dispatchers: HashMap<SocketAddr, Dispatcher>,
+    sockets: HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>,
+    closers: HashMap<SocketAddr, oneshot::Sender<()>>, // dropping one stops the client's reader
+    ids: HashMap<SocketAddr, u64>, // for logging, where only the address is at hand
+    heartbeats: HashMap<SocketAddr, JoinHandle<()>>,
+    observations: HashMap<(String, u16), Vec<(u16, u32)>>, // (plate, road) -> (mile, timestamp)
+    ticketed_days: HashMap<String, HashSet<u32>>,
+    road_dispatchers: HashMap<u16, Vec<SocketAddr>>,
+    road_limits: HashMap<u16, u16>, // road -> mph, as reported by its first camera
+    tolerance: u64,                 // 100x mph, like ticket speeds
+    pending_tickets: HashMap<u16, Vec<TicketPacket>>,
+}
+
+impl Server {
+    fn disconnect(&mut self, addr: SocketAddr) {
+        self.sockets.remove(&addr);
+        self.closers.remove(&addr);
+        self.ids.remove(&addr);
+        self.cameras.remove(&addr);
+        self.dispatchers.remove(&addr);
+        // Every road, not just those in `dispatchers`, so `dispatch_ticket` can never see it again
+        for addrs in self.road_dispatchers.values_mut() {
+            addrs.retain(|a| *a != addr);
+        }
+        if let Some(heartbeat) = self.heartbeats.remove(&addr) {
+            heartbeat.abort();
+        }
+    }
+
+    async fn reject(&mut self, addr: SocketAddr, id: u64, message: &str) {
+        error!("Rejecting client: {message} id={id} ip={addr}");
+        if let Some(write) = self.sockets.get(&addr) {
+            send_error(write, message).await;
+        }
+        self.disconnect(addr);
+    }
+
+    // Writes to a client, forgetting it when its connection is gone
+    async fn send(&mut self, addr: SocketAddr, bytes: &[u8]) -> bool {
+        let Some(write) = self.sockets.get(&addr).cloned() else {
+            self.disconnect(addr);
+            return false;
+        };
+
+        match write.lock().await.write_all(bytes).await {
+            Ok(()) => true,
+            Err(e) => {
+                let id = self.ids.get(&addr).copied().unwrap_or_default();
+                error!("Could not write to client: {e} id={id} ip={addr}");
+                self.disconnect(addr);
+                false
+            }
+        }
+    }
+
+    // Sends the ticket to a dispatcher for its road, or queues it until one connects
+    async fn dispatch_ticket(&mut self, ticket: TicketPacket) {
+        let bytes = ticket.serialize();
+        // A failed send disconnects the dispatcher, so the next one for the road is tried
+        while let Some(&addr) = self
+            .road_dispatchers
+            .get(&ticket.road)
+            .and_then(|addrs| addrs.first())
+        {
+            if self.send(addr, &bytes).await {
+                return;
+            }
+        }
+
+        self.pending_tickets
+            .entry(ticket.road)
+            .or_default()
+            .push(ticket);
+    }
+}
+
+async fn run_server(config: SpeedConfig, mut rx: UnboundedReceiver<MessageType>) {
+    let mut server = Server {
+        tolerance: (config.tolerance * 100.0).round() as u64,
+        ..Default::default()
+    };
+    while let Some(packet) = rx.recv().await {
+        match packet {
+            MessageType::ClientConnected(write, closer, addr, id) => {
+                info!("Client connected id={id} ip={addr}");
+                server.sockets.insert(addr, write);
+                server.closers.insert(addr, closer);
+                server.ids.insert(addr, id);
+            }
+            MessageType::ClientDisconnected(addr, id) => {
+                info!("Client disconnected id={id} ip={addr}");
+                server.disconnect(addr);
+            }
+            MessageType::Shutdown => {
+                for write in server.sockets.values() {
+                    let _ = write.lock().await.shutdown().await;
                 }
-                Err(_) => {
-                    error!("Could not deserialize packet");
+                break;
+            }
+            MessageType::IAmDispatcher(addr, id, packet) => {
+                if server.cameras.contains_key(&addr) || server.dispatchers.contains_key(&addr) {
+                    server.reject(addr, id, "already identified").await;
+                    continue;
                 }
-            },
-            Camera::OPCODE => match Camera::deserialize(&mut read).await {
-                Ok(packet) => {
-                    let _ = tx.send(MessageType::IAmCamera(addr, packet));
+
+                // Fully registered before any ticket is sent, so a failed write forgets it everywhere
+                let roads = packet.roads.clone();
+                for &road in &roads {
+                    server.road_dispatchers.entry(road).or_default().push(addr);
                 }
-                Err(_) => {
-                    error!("Could not deserialize packet");
+                server.dispatchers.insert(addr, packet);
+
+                for road in roads {
+                    if let Some(tickets) = server.pending_tickets.remove(&road) {
+                        for ticket in tickets {
+                            server.dispatch_ticket(ticket).await;
+                        }
+                    }
                 }
-            },
-            Dispatcher::OPCODE => match Dispatcher::deserialize(&mut read).await {
-                Ok(packet) => {
-                    let _ = tx.send(MessageType::IAmDispatcher(addr, packet));
+            }
+            MessageType::IAmCamera(addr, id, packet) => {
+                if server.cameras.contains_key(&addr) || server.dispatchers.contains_key(&addr) {
+                    server.reject(addr, id, "already identified").await;
+                    continue;
                 }
-                Err(_) => {
-                    error!("Could not deserialize packet");
+
+                let limit = *server
+                    .road_limits
+                    .entry(packet.road)
+                    .or_insert(packet.limit);
+                if limit != packet.limit {
+                    warn!(
+                        "Camera reported limit {} for road {} which is already {limit}, keeping {limit} id={id} ip={addr}",
+                        packet.limit, packet.road
+                    );
                 }
-            },
-            WantHeartBeatPacket::OPCODE => {
-                match WantHeartBeatPacket::deserialize(&mut read).await {
-                    Ok(packet) => {
-                        let _ = tx.send(MessageType::WantHeartBeat(addr, packet));
-                    }
-                    Err(_) => {
-                        error!("Could not deserialize packet");
+
+                server.cameras.insert(addr, packet);
+            }
+            MessageType::Plate(addr, id, plate) => {
+                let Some(camera) = server.cameras.get(&addr) else {
+                    server.reject(addr, id, "not a camera").await;
+                    continue;
+                };
+                let limit = server.road_limits[&camera.road];
+
+                let readings = server
+                    .observations
+                    .entry((plate.plate.clone(), camera.road))
+                    .or_default();
+
+                // Cameras may resend a reading, it carries nothing new
+                let reading = (camera.mile, plate.timestamp);
+                if readings.contains(&reading) {
+                    info!(
+                        "Ignoring repeated reading of plate {} id={id} ip={addr}",
+                        plate.plate
+                    );
+                    continue;
+                }
+
+                let mut tickets = Vec::new();
+                for &earlier in readings.iter() {
+                    let Some(ticket) = check_speed(
+                        &plate.plate,
+                        camera.road,
+                        limit,
+                        server.tolerance,
+                        earlier,
+                        reading,
+                    ) else {
+                        continue;
+                    };
+
+                    let days = ticket.timestamp1 / 86400..=ticket.timestamp2 / 86400;
+                    let plate_days = server
+                        .ticketed_days
+                        .entry(ticket.plate.clone())
+                        .or_default();
+                    if days.clone().any(|day| plate_days.contains(&day)) {
+                        info!(
+                            "Plate {} was already ticketed on one of its days",
+                            ticket.plate
+                        );
+                        continue;
                     }
+                    plate_days.extend(days);
+
+                    info!(
+                        "Plate {} went {} on road {} between {} and {}",
+                        ticket.plate,
+                        ticket.speed as f32 / 100.0,
+                        ticket.road,
+                        ticket.timestamp1,
+                        ticket.timestamp2
+                    );
+                    tickets.push(ticket);
+                }
+
+                readings.push(reading);
+
+                for ticket in tickets {
+                    server.dispatch_ticket(ticket).await;
                 }
             }
-            _ => {
-                error!("Received unknown packet");
-                break;
+            MessageType::WantHeartBeat(addr, id, packet) => {
+                if server.heartbeats.contains_key(&addr) {
+                    server.reject(addr, id, "heartbeat already requested").await;
+                    continue;
+                }
+
+                let Some(write) = server.sockets.get(&addr) else {
+                    error!(
+                        "Client requested heart beat but doesn't appear connected id={id} ip={addr}"
+                    );
+                    continue;
+                };
+
+                server.heartbeats.insert(
+                    addr,
+                    tokio::spawn(handle_heartbeat(write.clone(), addr, id, packet.interval)),
+                );
             }
-        }
+        };
     }
 }
 
-async fn run_server(mut rx: UnboundedReceiver<MessageType>) {
-    let mut cameras = HashMap::new();
-    let mut dispatchers = HashMap::new();
-    let mut sockets = HashMap::new();
-    let mut heartbeats = HashMap::new();
+// Replies with an error and closes the connection, as the spec requires for illegal messages
+async fn send_error(write: &Mutex<OwnedWriteHalf>, message: &str) {
+    let packet = ErrorPacket {
+        message: message.to_owned(),
+    }
+    .serialize();
+
+    let mut write = write.lock().await;
+    let _ = write.write_all(&packet).await;
+    let _ = write.shutdown().await;
+}
+
+// Both readings are `(mile, timestamp)` on the same road, in any order
+fn check_speed(
+    plate: &str,
+    road: u16,
+    limit: u16,
+    tolerance: u64,
+    a: (u16, u32),
+    b: (u16, u32),
+) -> Option<TicketPacket> {
+    let ((mile1, timestamp1), (mile2, timestamp2)) = if a.1 <= b.1 { (a, b) } else { (b, a) };
+    if timestamp1 == timestamp2 {
+        return None;
+    }
+
+    let distance = mile1.abs_diff(mile2) as u64;
+    let elapsed = (timestamp2 - timestamp1) as u64;
+    let speed = (distance * 3600 * 100 + elapsed / 2) / elapsed; // 100x miles per hour, rounded
+
+    if speed <= limit as u64 * 100 + tolerance {
+        return None;
+    }
+
+    Some(TicketPacket {
+        plate: plate.to_owned(),
+        road,
+        mile1,
+        timestamp1,
+        mile2,
+        timestamp2,
+        speed: speed.min(u16::MAX as u64) as u16,
+    })
+}
+
+async fn handle_heartbeat(
+    write: Arc<Mutex<OwnedWriteHalf>>,
+    addr: SocketAddr,
+    id: u64,
+    interval: u32,
+) {
+    if interval == 0 {
+        return;
+    }
+
+    let period = Duration::from_millis(interval as u64 * 100);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let heartbeat = HeartBeatPacket {}.serialize();
+
     loop {
-        match rx.recv().await {
-            Some(packet) => {
-                match packet {
-                    MessageType::ClientConnected(write, addr) => {
-                        sockets.insert(addr, Arc::new(Mutex::new(write)));
-                    }
-                    MessageType::ClientDisconnected(addr) => {
-                        sockets.remove(&addr);
-                        cameras.remove(&addr);
-                        dispatchers.remove(&addr);
-                    }
-                    MessageType::IAmDispatcher(addr, packet) => {
-                        dispatchers.insert(addr, packet);
-                    }
-                    MessageType::IAmCamera(addr, packet) => {
-                        cameras.insert(addr, packet);
-                    }
-                    MessageType::Plate(addr, plate) => {
-                        todo!();
-                    }
-                    MessageType::WantHeartBeat(addr, packet) => {
-                        let Some(write) = sockets.get(&addr) else {
-                            error!("Client requested heart beat but doesn't appear connected");
-                            continue;
-                        };
-                        heartbeats.insert(addr, tokio::spawn(handle_heartbeat(write.clone())));
-                    }
-                };
-            }
-            None => {}
+        ticker.tick().await;
+        if let Err(e) = write.lock().await.write_all(&heartbeat).await {
+            error!("Could not send heartbeat: {e} id={id} ip={addr}");
+            break;
         }
     }
 }
 
-async fn handle_heartbeat(write: Arc<Mutex<OwnedWriteHalf>>) {}
+pub struct SpeedServer;
 
-pub async fn run_speed() {
-    let listener = Arc::new(TcpListener::bind("0.0.0.0:8080").await.unwrap());
+#[async_trait]
+impl ProtoServer for SpeedServer {
+    fn name(&self) -> &str {
+        "speed"
+    }
+
+    fn description(&self) -> &str {
+        "Speed Daemon, a binary protocol for speed cameras"
+    }
+
+    async fn run(&self, port: u16) {
+        let listener = bind_tcp(BindMode::from_env(), port)
+            .unwrap_or_else(|e| panic!("Could not bind listener: {e}"));
+
+        let (_, server) = run_speed(SpeedConfig::from_env(), listener)
+            .unwrap_or_else(|e| panic!("Could not start the speed server: {e}"));
+        let _ = server.await;
+    }
+}
 
-    info!("🚀 Server listening on :8080");
+// Returns the address the listener is bound to and the task serving it, which finishes on Ctrl-C
+pub fn run_speed(
+    config: SpeedConfig,
+    listener: TcpListener,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let local_addr = listener.local_addr()?;
+    info!("🚀 Server listening on {local_addr}");
 
+    Ok((local_addr, tokio::spawn(serve(config, listener))))
+}
+
+async fn serve(config: SpeedConfig, listener: TcpListener) {
     let (tx, rx) = unbounded_channel::<MessageType>();
 
-    tokio::spawn(run_server(rx));
+    let identify_timeout = config.identify_timeout;
+    let server = tokio::spawn(run_server(config, rx));
 
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    let mut next_id: u64 = 0;
+    let mut backoff = AcceptBackoff::default();
     loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                tokio::spawn(handle_client(tx.clone(), stream, addr));
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
             }
-            Err(e) => {
-                error!("Could not accept connection: {e}");
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    backoff.reset();
+                    tokio::spawn(handle_client(tx.clone(), stream, addr, next_id, identify_timeout));
+                    next_id += 1;
+                }
+                Err(e) => {
+                    error!("Could not accept connection: {e}");
+                    backoff.wait().await;
+                }
             }
         }
     }
+
+    let _ = tx.send(MessageType::Shutdown);
+    let _ = server.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::Entry;
+
+    use super::*;
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // Plays a fixture against a server on an ephemeral loopback port. Each line is
+    // `<client> > <hex bytes>` for bytes the named client sends, `<client> < <hex bytes>` for
+    // bytes it must receive next, or `<client> eof` for the server closing it.
+    async fn replay(fixture: &str) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (addr, _) = run_speed(SpeedConfig::default(), listener).unwrap();
+
+        let mut clients: HashMap<&str, TcpStream> = HashMap::new();
+        for line in fixture
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            let mut parts = line.splitn(3, ' ');
+            let (name, op) = (parts.next().unwrap(), parts.next().unwrap());
+            let bytes: Vec<u8> = parts
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+                .collect();
+
+            let client = match clients.entry(name) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(TcpStream::connect(addr).await.unwrap()),
+            };
+
+            match op {
+                ">" => client.write_all(&bytes).await.unwrap(),
+                "<" => assert_eq!(recv(client, bytes.len()).await, bytes, "`{line}`"),
+                "eof" => assert_closed(client).await,
+                op => panic!("unknown fixture op `{op}`"),
+            }
+        }
+    }
+
+    async fn recv(client: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut received = vec![0; len];
+        tokio::time::timeout(RECV_TIMEOUT, client.read_exact(&mut received))
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {len} bytes"))
+            .unwrap();
+        received
+    }
+
+    async fn assert_closed(client: &mut TcpStream) {
+        let n = tokio::time::timeout(RECV_TIMEOUT, client.read(&mut [0; 1]))
+            .await
+            .expect("timed out waiting for the connection to close")
+            .unwrap();
+        assert_eq!(n, 0, "expected the connection to be closed");
+    }
+
+    // A `run_server` fed directly, without the listener and client tasks in front of it
+    fn spawn_server() -> UnboundedSender<MessageType> {
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(run_server(SpeedConfig::default(), rx));
+        tx
+    }
+
+    // Registers a loopback connection the way `handle_client` does, returning the address the
+    // server knows it by and the client's end of it
+    async fn connect(tx: &UnboundedSender<MessageType>, id: u64) -> (SocketAddr, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let (_, write) = stream.into_split();
+        let (closer, _) = oneshot::channel();
+
+        tx.send(MessageType::ClientConnected(
+            Arc::new(Mutex::new(write)),
+            closer,
+            addr,
+            id,
+        ))
+        .unwrap();
+        (addr, client)
+    }
+
+    fn error(message: &str) -> Vec<u8> {
+        ErrorPacket {
+            message: message.to_owned(),
+        }
+        .serialize()
+    }
+
+    #[tokio::test]
+    async fn identifying_twice_is_an_error() {
+        let tx = spawn_server();
+
+        let (addr, mut camera) = connect(&tx, 0).await;
+        tx.send(MessageType::IAmCamera(addr, 0, Camera::new(123, 8, 60)))
+            .unwrap();
+        tx.send(MessageType::IAmCamera(addr, 0, Camera::new(123, 9, 60)))
+            .unwrap();
+        let expected = error("already identified");
+        assert_eq!(recv(&mut camera, expected.len()).await, expected);
+        assert_closed(&mut camera).await;
+
+        let (addr, mut dispatcher) = connect(&tx, 1).await;
+        tx.send(MessageType::IAmDispatcher(
+            addr,
+            1,
+            Dispatcher::new(vec![123]),
+        ))
+        .unwrap();
+        tx.send(MessageType::IAmDispatcher(
+            addr,
+            1,
+            Dispatcher::new(vec![124]),
+        ))
+        .unwrap();
+        assert_eq!(recv(&mut dispatcher, expected.len()).await, expected);
+        assert_closed(&mut dispatcher).await;
+    }
+
+    #[tokio::test]
+    async fn a_client_cannot_be_both_camera_and_dispatcher() {
+        let tx = spawn_server();
+        let expected = error("already identified");
+
+        let (addr, mut client) = connect(&tx, 0).await;
+        tx.send(MessageType::IAmCamera(addr, 0, Camera::new(123, 8, 60)))
+            .unwrap();
+        tx.send(MessageType::IAmDispatcher(
+            addr,
+            0,
+            Dispatcher::new(vec![123]),
+        ))
+        .unwrap();
+        assert_eq!(recv(&mut client, expected.len()).await, expected);
+        assert_closed(&mut client).await;
+
+        let (addr, mut client) = connect(&tx, 1).await;
+        tx.send(MessageType::IAmDispatcher(
+            addr,
+            1,
+            Dispatcher::new(vec![123]),
+        ))
+        .unwrap();
+        tx.send(MessageType::IAmCamera(addr, 1, Camera::new(123, 8, 60)))
+            .unwrap();
+        assert_eq!(recv(&mut client, expected.len()).await, expected);
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn tickets_readings_in_time_order_whatever_order_they_arrive_in() {
+        let tx = spawn_server();
+        let (later, _later_client) = connect(&tx, 0).await;
+        let (earlier, _earlier_client) = connect(&tx, 1).await;
+        let (dispatcher, mut dispatcher_client) = connect(&tx, 2).await;
+
+        tx.send(MessageType::IAmCamera(later, 0, Camera::new(123, 9, 60)))
+            .unwrap();
+        tx.send(MessageType::Plate(
+            later,
+            0,
+            PlatePacket {
+                plate: String::from("UN1X"),
+                timestamp: 45,
+            },
+        ))
+        .unwrap();
+        tx.send(MessageType::IAmCamera(earlier, 1, Camera::new(123, 8, 60)))
+            .unwrap();
+        tx.send(MessageType::Plate(
+            earlier,
+            1,
+            PlatePacket {
+                plate: String::from("UN1X"),
+                timestamp: 0,
+            },
+        ))
+        .unwrap();
+        tx.send(MessageType::IAmDispatcher(
+            dispatcher,
+            2,
+            Dispatcher::new(vec![123]),
+        ))
+        .unwrap();
+
+        // Mile 8 at 0 comes first even though it was reported second
+        let expected = ticket(123).serialize();
+        assert_eq!(recv(&mut dispatcher_client, expected.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn disconnect_stops_the_heartbeat() {
+        let tx = spawn_server();
+        let (addr, mut client) = connect(&tx, 0).await;
+
+        tx.send(MessageType::WantHeartBeat(
+            addr,
+            0,
+            WantHeartBeatPacket { interval: 1 },
+        ))
+        .unwrap();
+        assert_eq!(recv(&mut client, 1).await, [0x41]);
+        tx.send(MessageType::ClientDisconnected(addr, 0)).unwrap();
+
+        // The heartbeat task holds the last handle to the write half, the socket only closes
+        // once it is gone. Beats already on their way may still arrive first.
+        let mut rest = Vec::new();
+        tokio::time::timeout(RECV_TIMEOUT, client.read_to_end(&mut rest))
+            .await
+            .expect("the heartbeat kept the connection open")
+            .unwrap();
+        assert!(rest.iter().all(|&b| b == 0x41));
+    }
+
+    #[tokio::test]
+    async fn replays_spec_ticket_session() {
+        replay(include_str!("../fixtures/speed/ticket.txt")).await;
+    }
+
+    #[tokio::test]
+    async fn replays_heartbeat_session() {
+        replay(include_str!("../fixtures/speed/heartbeat.txt")).await;
+    }
+
+    #[tokio::test]
+    async fn replays_illegal_message_session() {
+        replay(include_str!("../fixtures/speed/illegal.txt")).await;
+    }
+
+    fn ticket(road: u16) -> TicketPacket {
+        TicketPacket {
+            plate: String::from("UN1X"),
+            road,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        }
+    }
+
+    #[test]
+    fn camera_serializes_to_spec_bytes() {
+        assert_eq!(
+            Camera::new(66, 100, 60).serialize(),
+            [0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c]
+        );
+    }
+
+    #[test]
+    fn dispatcher_serializes_to_spec_bytes() {
+        let dispatcher = Dispatcher::new(vec![66, 368, 5000]);
+        assert_eq!(dispatcher.numroads, 3);
+        assert_eq!(
+            dispatcher.serialize(),
+            [0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88]
+        );
+        assert_eq!(Dispatcher::new(Vec::new()).serialize(), [0x81, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn dispatcher_with_three_roads_round_trips() {
+        let bytes = [0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88];
+
+        let Ok(ClientPacket::IAmDispatcher(dispatcher)) =
+            ClientPacket::deserialize(&mut &bytes[..]).await
+        else {
+            panic!("did not decode as IAmDispatcher");
+        };
+        assert_eq!(dispatcher.numroads, 3);
+        assert_eq!(dispatcher.roads, [66, 368, 5000]);
+        assert_eq!(dispatcher.serialize(), bytes);
+    }
+
+    #[test]
+    fn ticket_serializes_to_spec_bytes() {
+        let ticket = TicketPacket {
+            plate: String::from("UN1X"),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        };
+
+        assert_eq!(
+            ticket.serialize(),
+            [
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatch_ticket_gives_up_on_a_dispatcher_without_a_socket() {
+        let mut server = Server::default();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.road_dispatchers.insert(123, vec![addr]);
+
+        tokio::time::timeout(Duration::from_secs(2), server.dispatch_ticket(ticket(123)))
+            .await
+            .expect("dispatch_ticket kept retrying the same dispatcher");
+
+        assert!(server.road_dispatchers[&123].is_empty());
+        assert_eq!(server.pending_tickets[&123].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_client_is_closed_and_its_reader_stops() {
+        let tx = spawn_server();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let reader = tokio::spawn(handle_client(tx, stream, addr, 0, None));
+
+        let camera = Camera::new(123, 8, 60).serialize();
+        client.write_all(&camera).await.unwrap();
+        client.write_all(&camera).await.unwrap();
+        let expected = error("already identified");
+        assert_eq!(recv(&mut client, expected.len()).await, expected);
+        assert_closed(&mut client).await;
+
+        tokio::time::timeout(RECV_TIMEOUT, reader)
+            .await
+            .expect("handle_client kept reading after the rejection")
+            .unwrap();
+    }
 }
@@ -1,111 +1,1137 @@
-use std::{char, collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use lazy_static::lazy_static;
-use log::{error, info};
+use async_trait::async_trait;
+use log::{error, info, warn};
 use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
     net::UdpSocket,
     sync::{
-        RwLock,
+        Mutex, RwLock,
         mpsc::{UnboundedReceiver, unbounded_channel},
+        oneshot,
     },
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    bind::{BindMode, bind_udp},
+    proto_server::ProtoServer,
 };
 
-const VERSION: &str = "version=Ken's Key-Value Store 1.0";
+const DEFAULT_VERSION: &str = "Ken's Key-Value Store 1.0";
+const MAX_PACKET_SIZE: usize = 1000;
+// One byte of headroom, so a datagram that got truncated by the buffer can be detected
+const RECV_BUFFER_SIZE: usize = MAX_PACKET_SIZE + 1;
+const DELETE_PREFIX: char = '\0'; // with `delete` on, `\0key` deletes `key`
+const TTL_SEPARATOR: char = '?'; // `key?30=value` expires `key` after 30 seconds
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SHARD_COUNT: usize = 16;
+const AUDIT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DUMP_KEY: &str = "__dump__"; // with `dump` on, retrieving it replies with every key
+
+type Shard = RwLock<HashMap<String, (String, Option<Instant>)>>; // key -> (value, expiry)
+
+// Keys are spread over several locks by hash, so writers to different keys don't contend
+struct Store {
+    shards: Vec<Shard>,
+    len: AtomicUsize, // keys held across every shard, expired ones count until they are evicted
+}
+
+impl Store {
+    fn new() -> Self {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| RwLock::new(HashMap::new()))
+            .collect();
+
+        Self {
+            shards,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    // Expired keys read as absent and are evicted on the spot
+    async fn get(&self, key: &str) -> Option<String> {
+        let shard = self.shard(key);
+        let data = shard.read().await;
+        let (value, expires) = data.get(key)?;
+
+        if expires.is_some_and(|expires| expires <= Instant::now()) {
+            drop(data);
+            // Checked again, the key may have been written in between the locks
+            let mut data = shard.write().await;
+            if data.get(key).is_some_and(|(_, expires)| {
+                expires.is_some_and(|expires| expires <= Instant::now())
+            }) {
+                data.remove(key);
+                self.len.fetch_sub(1, Ordering::Relaxed);
+            }
+            return None;
+        }
+
+        Some(value.clone())
+    }
+
+    // Every key that hasn't expired with its value, in no particular order
+    async fn entries(&self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(
+                shard
+                    .read()
+                    .await
+                    .iter()
+                    .filter(|(_, (_, expires))| expires.is_none_or(|expires| expires > now))
+                    .map(|(key, (value, _))| (key.clone(), value.clone())),
+            );
+        }
+        entries
+    }
+
+    // Overwrites always succeed, a new key is refused once the store holds `max_keys`. The slot is
+    // reserved under the shard lock, so two new keys can't both take the last one.
+    async fn insert(
+        &self,
+        key: String,
+        value: String,
+        expires: Option<Instant>,
+        max_keys: Option<usize>,
+    ) -> bool {
+        let mut data = self.shard(&key).write().await;
+        if !data.contains_key(&key)
+            && self
+                .len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| match max_keys {
+                    Some(max_keys) if len >= max_keys => None,
+                    _ => Some(len + 1),
+                })
+                .is_err()
+        {
+            return false;
+        }
+        data.insert(key, (value, expires));
+        true
+    }
+
+    async fn remove(&self, key: &str) {
+        if self.shard(key).write().await.remove(key).is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Returns how many keys were evicted
+    async fn evict_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut evicted = 0;
+        for shard in &self.shards {
+            let mut data = shard.write().await;
+            let before = data.len();
+            data.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+            evicted += before - data.len();
+        }
+        self.len.fetch_sub(evicted, Ordering::Relaxed);
+        evicted
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[shard_index(key)]
+    }
+}
+
+fn shard_index(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARD_COUNT
+}
 
-lazy_static! {
-    static ref DATA: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+pub struct UnusualConfig {
+    pub log_path: Option<PathBuf>, // append-only log of inserts, replayed on startup
+    pub version: String,           // reported for the read-only `version` key
+    pub audit_path: Option<PathBuf>, // JSON lines record of every request, off by default
+    pub max_value_len: Option<usize>, // longer values are refused, `None` is unlimited
+    pub max_keys: Option<usize>,   // new keys are refused once the store holds this many
+    pub nack: bool, // reply `error=...` to refused requests instead of staying silent
+    pub ttl: bool,  // accept `key?30=value` inserts that expire, off as the spec allows `?` in keys
+    pub dump: bool, // retrieving `__dump__` replies with the whole store, off as anyone could read it
+    pub delete: bool, // `\0key` deletes `key`, off as the spec allows keys starting with NUL
+    pub multi_get: bool, // newline separated keys are retrieved at once, off as keys may hold newlines
+}
+
+impl Default for UnusualConfig {
+    fn default() -> Self {
+        Self {
+            log_path: None,
+            version: DEFAULT_VERSION.to_owned(),
+            audit_path: None,
+            max_value_len: None,
+            max_keys: None,
+            nack: false,
+            ttl: false,
+            dump: false,
+            delete: false,
+            multi_get: false,
+        }
+    }
+}
+
+impl UnusualConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        let log_path = env::var("UNUSUAL_LOG_PATH")
+            .ok()
+            .map(PathBuf::from)
+            .or(defaults.log_path);
+        let version = env::var("UNUSUAL_VERSION").unwrap_or(defaults.version);
+
+        let audit_path = env::var("UNUSUAL_AUDIT_PATH")
+            .ok()
+            .map(PathBuf::from)
+            .or(defaults.audit_path);
+
+        let max_value_len = env::var("UNUSUAL_MAX_VALUE_LEN")
+            .ok()
+            .map_or(defaults.max_value_len, |v| {
+                Some(v.parse().expect("UNUSUAL_MAX_VALUE_LEN must be a number"))
+            });
+        let max_keys = env::var("UNUSUAL_MAX_KEYS")
+            .ok()
+            .map_or(defaults.max_keys, |v| {
+                Some(v.parse().expect("UNUSUAL_MAX_KEYS must be a number"))
+            });
+
+        let nack = env::var("UNUSUAL_NACK").map_or(defaults.nack, |v| v == "1" || v == "true");
+        let ttl = env::var("UNUSUAL_TTL").map_or(defaults.ttl, |v| v == "1" || v == "true");
+        let dump = env::var("UNUSUAL_DUMP").map_or(defaults.dump, |v| v == "1" || v == "true");
+        let delete =
+            env::var("UNUSUAL_DELETE").map_or(defaults.delete, |v| v == "1" || v == "true");
+        let multi_get =
+            env::var("UNUSUAL_MULTI_GET").map_or(defaults.multi_get, |v| v == "1" || v == "true");
+
+        Self {
+            log_path,
+            version,
+            audit_path,
+            max_value_len,
+            max_keys,
+            nack,
+            ttl,
+            dump,
+            delete,
+            multi_get,
+        }
+    }
 }
 
 enum Message {
-    Insert(SocketAddr, String, String),
+    Insert(SocketAddr, String, String, Option<Duration>),
     Retrieve(SocketAddr, String),
+    RetrieveMany(SocketAddr, Vec<String>),
+    Delete(SocketAddr, String),
+    Dump(SocketAddr),
+}
+
+// Buffered, so requests don't each wait on the disk. `run_server` flushes it periodically.
+struct Audit {
+    writer: BufWriter<File>,
+}
+
+impl Audit {
+    async fn record(&mut self, addr: SocketAddr, op: &str, key: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let line = format!(
+            "{{\"timestamp\":{timestamp:.3},\"addr\":\"{addr}\",\"op\":\"{op}\",\"key\":{}}}\n",
+            json_string(key)
+        );
+
+        if let Err(e) = self.writer.write_all(line.as_bytes()).await {
+            error!("Could not write to the audit log: {e}");
+        }
+    }
+
+    async fn flush(&mut self) {
+        if let Err(e) = self.writer.flush().await {
+            error!("Could not flush the audit log: {e}");
+        }
+    }
 }
 
-async fn run_server(socket: Arc<UdpSocket>, mut rx: UnboundedReceiver<Message>) {
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Everything the workers handling requests share
+struct Shared {
+    socket: Arc<UdpSocket>,
+    store: Arc<Store>,
+    log: Option<Mutex<File>>,
+    audit: Option<Mutex<Audit>>,
+    config: UnusualConfig,
+    version_reply: String,
+}
+
+// What a shard worker is handed, in the order the requests came in
+enum Job {
+    Handle(Message),
+    Barrier(oneshot::Sender<()>), // answered once every job queued before it is done
+}
+
+// Requests for a single key go to the worker owning its shard, so they are handled in the order
+// they came in while other shards make progress. Dumps and multi-key retrieves span shards and are
+// handled in a task of their own, once every shard they read has caught up with earlier requests.
+async fn run_server(mut rx: UnboundedReceiver<Message>, shared: Arc<Shared>) {
+    let mut workers = Vec::with_capacity(SHARD_COUNT);
+    let mut senders = Vec::with_capacity(SHARD_COUNT);
+    for _ in 0..SHARD_COUNT {
+        let (tx, rx) = unbounded_channel();
+        workers.push(tokio::spawn(run_worker(rx, shared.clone())));
+        senders.push(tx);
+    }
+    let mut spanning = JoinSet::new();
+
+    let mut flush = tokio::time::interval(AUDIT_FLUSH_INTERVAL);
     loop {
-        match rx.recv().await {
-            Some(message) => {
-                match message {
-                    Message::Insert(addr, key, value) => {
-                        info!("Client {addr} sent a insert request for `{key}` of `{value}`");
-                        if key == "version" {
-                            continue;
-                        }
-                        let mut data = DATA.write().await;
-                        data.insert(key, value);
+        let message = tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = flush.tick() => {
+                if let Some(audit) = &shared.audit {
+                    audit.lock().await.flush().await;
+                }
+                continue;
+            }
+        };
+        while spanning.try_join_next().is_some() {}
+
+        let shards = match &message {
+            Message::Insert(_, key, ..) | Message::Delete(_, key) | Message::Retrieve(_, key) => {
+                let _ = senders[shard_index(key)].send(Job::Handle(message));
+                continue;
+            }
+            Message::RetrieveMany(_, keys) => {
+                let mut shards: Vec<_> = keys.iter().map(|key| shard_index(key)).collect();
+                shards.sort_unstable();
+                shards.dedup();
+                shards
+            }
+            Message::Dump(_) => (0..SHARD_COUNT).collect(),
+        };
+
+        let barriers: Vec<_> = shards
+            .into_iter()
+            .map(|shard| {
+                let (tx, rx) = oneshot::channel();
+                let _ = senders[shard].send(Job::Barrier(tx));
+                rx
+            })
+            .collect();
+        let shared = shared.clone();
+        spanning.spawn(async move {
+            for barrier in barriers {
+                let _ = barrier.await;
+            }
+            handle_message(&shared, message).await;
+        });
+    }
+
+    // Dropping the senders lets each worker finish its queue and exit
+    drop(senders);
+    for worker in workers {
+        let _ = worker.await;
+    }
+    spanning.join_all().await;
+
+    if let Some(audit) = &shared.audit {
+        audit.lock().await.flush().await;
+    }
+}
+
+async fn run_worker(mut rx: UnboundedReceiver<Job>, shared: Arc<Shared>) {
+    while let Some(job) = rx.recv().await {
+        match job {
+            Job::Handle(message) => handle_message(&shared, message).await,
+            Job::Barrier(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+async fn handle_message(shared: &Shared, message: Message) {
+    match message {
+        Message::Insert(addr, key, value, ttl) => {
+            info!("Client {addr} sent a insert request for `{key}` of `{value}`");
+            if let Some(audit) = &shared.audit {
+                audit.lock().await.record(addr, "insert", &key).await;
+            }
+            if key == "version" {
+                return;
+            }
+            if let Some(max_value_len) = shared.config.max_value_len
+                && value.len() > max_value_len
+            {
+                warn!(
+                    "Client {addr} sent a {} byte value for `{key}`, over the limit of {max_value_len}",
+                    value.len()
+                );
+                if shared.config.nack {
+                    send_nack(&shared.socket, addr, "value too long").await;
+                }
+                return;
+            }
+            // Expiring keys are not persisted, the deletion keeps an older value from coming back on replay
+            let record = match ttl {
+                Some(_) => log_record(&key, None),
+                None => log_record(&key, Some(&value)),
+            };
+            let expires = ttl.map(|ttl| Instant::now() + ttl);
+            if !shared
+                .store
+                .insert(key.clone(), value, expires, shared.config.max_keys)
+                .await
+            {
+                warn!("Store is full, not inserting `{key}` from {addr}");
+                if shared.config.nack {
+                    send_nack(&shared.socket, addr, "store full").await;
+                }
+                return;
+            }
+            if let Some(log) = &shared.log
+                && let Err(e) = log.lock().await.write_all(&record).await
+            {
+                error!("Could not append `{key}` to the log: {e}");
+            }
+        }
+        Message::Delete(addr, key) => {
+            info!("Client {addr} sent a delete request for `{key}`");
+            if let Some(audit) = &shared.audit {
+                audit.lock().await.record(addr, "delete", &key).await;
+            }
+            if key == "version" {
+                return;
+            }
+            if let Some(log) = &shared.log
+                && let Err(e) = log.lock().await.write_all(&log_record(&key, None)).await
+            {
+                error!("Could not append the deletion of `{key}` to the log: {e}");
+            }
+            shared.store.remove(&key).await;
+        }
+        Message::Retrieve(addr, key) => {
+            info!("Client {addr} sent a get request for `{key}`");
+            if let Some(audit) = &shared.audit {
+                audit.lock().await.record(addr, "retrieve", &key).await;
+            }
+            match key.as_str() {
+                "version" => {
+                    if shared
+                        .socket
+                        .send_to(shared.version_reply.as_bytes(), addr)
+                        .await
+                        .is_err()
+                    {
+                        error!("Failed to reply to {addr} about key `{key}`");
                     }
-                    Message::Retrieve(addr, key) => {
-                        info!("Client {addr} sent a get request for `{key}`");
-                        match key.as_str() {
-                            "version" => {
-                                if socket.send_to(VERSION.as_bytes(), addr).await.is_err() {
-                                    error!("Failed to reply to {addr} about key `{key}`");
-                                }
-                            }
-                            key => {
-                                let data = DATA.read().await;
-                                let Some(value) = data.get(key) else {
-                                    info!("Client {addr} requested inexistent key `{key}`");
-                                    continue;
-                                };
-
-                                let mut reply = String::with_capacity(key.len() + value.len() + 1); // both strings + `=`
-                                reply.push_str(key);
-                                reply.push('=');
-                                reply.push_str(value);
-
-                                let to_send;
-                                if reply.len() > 1000 {
-                                    to_send = reply[0..1000].as_bytes();
-                                } else {
-                                    to_send = reply.as_bytes();
-                                }
-
-                                if socket.send_to(to_send, addr).await.is_err() {
-                                    error!("Failed to reply to {addr} about key `{key}`");
-                                }
-                            }
-                        };
+                }
+                key => {
+                    let Some(value) = shared.store.get(key).await else {
+                        info!("Client {addr} requested inexistent key `{key}`");
+                        return;
+                    };
+
+                    let mut reply = String::with_capacity(key.len() + value.len() + 1); // both strings + `=`
+                    reply.push_str(key);
+                    reply.push('=');
+                    reply.push_str(&value);
+
+                    let to_send = if reply.len() > MAX_PACKET_SIZE {
+                        &reply.as_bytes()[0..MAX_PACKET_SIZE]
+                    } else {
+                        reply.as_bytes()
+                    };
+
+                    if shared.socket.send_to(to_send, addr).await.is_err() {
+                        error!("Failed to reply to {addr} about key `{key}`");
                     }
+                }
+            };
+        }
+        Message::RetrieveMany(addr, keys) => {
+            info!("Client {addr} sent a get request for {} keys", keys.len());
+            if let Some(audit) = &shared.audit {
+                let mut audit = audit.lock().await;
+                for key in &keys {
+                    audit.record(addr, "retrieve", key).await;
+                }
+            }
+            let mut reply = String::new();
+            for key in keys {
+                let value = match key.as_str() {
+                    "version" => Some(shared.config.version.clone()),
+                    key => shared.store.get(key).await,
                 };
+                if let Some(value) = value {
+                    reply.push_str(&key);
+                    reply.push('=');
+                    reply.push_str(&value);
+                    reply.push('\n');
+                }
             }
-            None => {
-                break;
+
+            if reply.is_empty() {
+                info!("Client {addr} requested only inexistent keys");
+                return;
+            }
+
+            let to_send = &reply.as_bytes()[..reply.len().min(MAX_PACKET_SIZE)];
+            if shared.socket.send_to(to_send, addr).await.is_err() {
+                error!("Failed to reply to {addr} about several keys");
+            }
+        }
+        Message::Dump(addr) => {
+            info!("Client {addr} sent a dump request");
+            if let Some(audit) = &shared.audit {
+                audit.lock().await.record(addr, "dump", DUMP_KEY).await;
+            }
+            let chunks = dump_chunks(shared.store.entries().await);
+            info!("Dumping the store to {addr} in {} datagrams", chunks.len());
+            for chunk in chunks {
+                if shared.socket.send_to(chunk.as_bytes(), addr).await.is_err() {
+                    error!("Failed to send a dump to {addr}");
+                    break;
+                }
             }
         }
+    };
+}
+
+// Each record is `<key length> <value length>\n<key><value>\n`, so keys and values may hold anything.
+// Deletions are written as `<key length> -\n<key>\n`.
+fn log_record(key: &str, value: Option<&str>) -> Vec<u8> {
+    match value {
+        Some(value) => format!("{} {}\n{}{}\n", key.len(), value.len(), key, value).into_bytes(),
+        None => format!("{} -\n{}\n", key.len(), key).into_bytes(),
     }
 }
 
-pub async fn run_unusual() {
-    let socket = Arc::new(UdpSocket::bind("0.0.0.0:8080").await.unwrap());
+async fn replay_log(path: &Path, store: &Store) -> std::io::Result<usize> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
 
-    info!("🚀 Server listening on :8080");
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupted log record");
+    let mut rest = contents.as_str();
+    let mut count = 0;
+    while !rest.is_empty() {
+        let (header, body) = rest.split_once('\n').ok_or_else(invalid)?;
+        let (key_len, value_len) = header.split_once(' ').ok_or_else(invalid)?;
+        let key_len: usize = key_len.parse().map_err(|_| invalid())?;
+        let key = body.get(..key_len).ok_or_else(invalid)?;
+
+        if value_len == "-" {
+            rest = body
+                .get(key_len..)
+                .and_then(|r| r.strip_prefix('\n'))
+                .ok_or_else(invalid)?;
+            store.remove(key).await;
+            count += 1;
+            continue;
+        }
+
+        let value_len: usize = value_len.parse().map_err(|_| invalid())?;
+        let value = body.get(key_len..key_len + value_len).ok_or_else(invalid)?;
+        rest = body
+            .get(key_len + value_len..)
+            .and_then(|r| r.strip_prefix('\n'))
+            .ok_or_else(invalid)?;
+
+        store
+            .insert(key.to_owned(), value.to_owned(), None, None)
+            .await;
+        count += 1;
+    }
 
+    Ok(count)
+}
+
+// `key=value\n` lines packed into as few datagrams as fit, a line too long for one is truncated
+fn dump_chunks(entries: Vec<(String, String)>) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for (key, value) in entries {
+        let mut line = format!("{key}={value}\n");
+        if line.len() > MAX_PACKET_SIZE {
+            let mut end = MAX_PACKET_SIZE;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        if chunk.len() + line.len() > MAX_PACKET_SIZE {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push_str(&line);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+async fn sweep_expired(store: Arc<Store>) {
+    let mut ticker = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let evicted = store.evict_expired().await;
+        if evicted > 0 {
+            info!("Evicted {evicted} expired keys");
+        }
+    }
+}
+
+// The first `=` splits an insert, so `foo=a=b` stores `a=b` under `foo` and a key can never hold `=`.
+// Anything without an `=` is a retrieve (or a delete with the prefix), whatever else it contains,
+// and newline separated keys are all retrieved at once. The extensions the config leaves off are
+// plain keys, so `?` is just part of a key without `ttl`, `__dump__` is stored without `dump`,
+// `\0key` is retrieved without `delete` and `a\nb` is a single key without `multi_get`.
+fn parse_message(addr: SocketAddr, message: &str, config: &UnusualConfig) -> Message {
+    if config.dump && message == DUMP_KEY {
+        return Message::Dump(addr);
+    }
+
+    match message.split_once('=') {
+        Some((key, value)) => {
+            let (key, ttl) = if config.ttl {
+                parse_ttl(key)
+            } else {
+                (key, None)
+            };
+            Message::Insert(addr, key.to_owned(), value.to_owned(), ttl)
+        }
+        None => match message
+            .strip_prefix(DELETE_PREFIX)
+            .filter(|_| config.delete)
+        {
+            Some(key) => Message::Delete(addr, key.to_owned()),
+            None if config.multi_get && message.contains('\n') => {
+                Message::RetrieveMany(addr, message.lines().map(str::to_owned).collect())
+            }
+            None => Message::Retrieve(addr, message.to_owned()),
+        },
+    }
+}
+
+// Splits a `key?ttl` insert key into the key and its time to live, if it has one
+fn parse_ttl(key: &str) -> (&str, Option<Duration>) {
+    match key.rsplit_once(TTL_SEPARATOR) {
+        Some((key, ttl)) if !ttl.is_empty() && ttl.bytes().all(|b| b.is_ascii_digit()) => {
+            match ttl.parse() {
+                Ok(secs) => (key, Some(Duration::from_secs(secs))),
+                Err(_) => (key, None),
+            }
+        }
+        _ => (key, None),
+    }
+}
+
+pub struct UnusualServer;
+
+#[async_trait]
+impl ProtoServer for UnusualServer {
+    fn name(&self) -> &str {
+        "unusual"
+    }
+
+    fn description(&self) -> &str {
+        "Unusual Database Program, a key-value store over UDP"
+    }
+
+    async fn run(&self, port: u16) {
+        let socket = bind_udp(BindMode::from_env(), port)
+            .unwrap_or_else(|e| panic!("Could not bind socket: {e}"));
+
+        let (_, server) = run_unusual(UnusualConfig::from_env(), socket)
+            .await
+            .unwrap_or_else(|e| panic!("Could not start the unusual server: {e}"));
+        let _ = server.await;
+    }
+}
+
+// Replays the log, if any, before serving. Returns the address the socket is bound to and the
+// task serving it, which finishes on Ctrl-C.
+pub async fn run_unusual(
+    config: UnusualConfig,
+    socket: UdpSocket,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let store = Arc::new(Store::new());
+
+    let log = match &config.log_path {
+        Some(path) => {
+            let count = replay_log(path, &store)
+                .await
+                .map_err(|e| with_path(e, "replay", path))?;
+            info!("Replayed {count} records from {}", path.display());
+
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|e| with_path(e, "open", path))?;
+            Some(file)
+        }
+        None => None,
+    };
+
+    let audit = match &config.audit_path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|e| with_path(e, "open", path))?;
+            Some(Audit {
+                writer: BufWriter::new(file),
+            })
+        }
+        None => None,
+    };
+
+    let local_addr = socket.local_addr()?;
+    info!("🚀 Server listening on {local_addr}");
+
+    Ok((
+        local_addr,
+        tokio::spawn(serve(Arc::new(socket), store, log, audit, config)),
+    ))
+}
+
+// Keeps the kind, so callers can still tell a missing file from a corrupted one
+fn with_path(e: std::io::Error, action: &str, path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Could not {action} {}: {e}", path.display()),
+    )
+}
+
+// The spec has bad requests ignored, this tells the client why for when it is being debugged
+async fn send_nack(socket: &UdpSocket, addr: SocketAddr, reason: &str) {
+    if socket
+        .send_to(format!("error={reason}").as_bytes(), addr)
+        .await
+        .is_err()
+    {
+        error!("Failed to tell {addr} its request was refused");
+    }
+}
+
+async fn serve(
+    socket: Arc<UdpSocket>,
+    store: Arc<Store>,
+    log: Option<File>,
+    audit: Option<Audit>,
+    config: UnusualConfig,
+) {
     let (tx, rx) = unbounded_channel();
 
-    tokio::spawn(run_server(socket.clone(), rx));
+    let nack = config.nack;
+    let ttl = config.ttl;
+    let shared = Arc::new(Shared {
+        socket: socket.clone(),
+        store: store.clone(),
+        log: log.map(Mutex::new),
+        audit: audit.map(Mutex::new),
+        version_reply: format!("version={}", config.version),
+        config,
+    });
+    let server = tokio::spawn(run_server(rx, shared.clone()));
+    // Nothing can expire without `ttl`, so there is nothing to sweep
+    let sweeper = ttl.then(|| tokio::spawn(sweep_expired(store)));
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
 
-    let mut buf = [0u8; 1000];
+    // Reused for every datagram, only the `n` bytes just received are ever looked at
+    let mut buf = [0u8; RECV_BUFFER_SIZE];
     loop {
-        match socket.recv_from(&mut buf).await {
-            Ok((n, addr)) => {
-                info!("Received {n} bytes from {addr}");
+        let received = tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            }
+            received = socket.recv_from(&mut buf) => received,
+        };
 
-                let Ok(message) = std::str::from_utf8(&buf[..n]) else {
-                    error!("Client did not send valid utf8 message");
-                    continue;
-                };
+        if let Ok((n, addr)) = received {
+            info!("Received {n} bytes from {addr}");
 
-                info!("Received the string `{message}`");
+            if n > MAX_PACKET_SIZE {
+                warn!("Client {addr} sent a request over {MAX_PACKET_SIZE} bytes, dropping it");
+                if nack {
+                    send_nack(&socket, addr, "request too long").await;
+                }
+                continue;
+            }
 
-                let _ = match message.split_once("=") {
-                    Some((key, value)) => {
-                        tx.send(Message::Insert(addr, key.to_owned(), value.to_owned()))
-                    }
-                    None => tx.send(Message::Retrieve(addr, message.to_owned())),
-                };
+            let Ok(message) = std::str::from_utf8(&buf[..n]) else {
+                error!("Client did not send valid utf8 message");
+                if nack {
+                    send_nack(&socket, addr, "invalid utf8").await;
+                }
+                continue;
+            };
+
+            info!("Received the string `{message}`");
+
+            let _ = tx.send(parse_message(addr, message, &shared.config));
+        }
+    }
+
+    // Dropping the sender lets `run_server` finish the queued requests and exit
+    drop(tx);
+    let _ = server.await;
+    if let Some(sweeper) = sweeper {
+        sweeper.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // Plays a fixture against a server on an ephemeral loopback port. Each line is `> <datagram>`
+    // for a request or `< <datagram>` for the reply that must come next, a bare `>` is empty.
+    async fn replay(fixture: &str) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (addr, _) = run_unusual(UnusualConfig::default(), socket).await.unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+
+        let mut buf = [0u8; RECV_BUFFER_SIZE];
+        for line in fixture
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            let (op, datagram) = line.split_at(1);
+            let datagram = datagram.strip_prefix(' ').unwrap_or(datagram);
+
+            match op {
+                ">" => {
+                    client.send(datagram.as_bytes()).await.unwrap();
+                }
+                "<" => {
+                    let n = tokio::time::timeout(RECV_TIMEOUT, client.recv(&mut buf))
+                        .await
+                        .unwrap_or_else(|_| panic!("timed out on `{line}`"))
+                        .unwrap();
+                    assert_eq!(std::str::from_utf8(&buf[..n]).unwrap(), datagram);
+                }
+                op => panic!("unknown fixture op `{op}`"),
             }
-            Err(_) => {}
         }
     }
+
+    #[test]
+    fn only_the_first_equals_sign_splits_an_insert() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        assert!(matches!(
+            parse_message(addr, "foo=a=b", &UnusualConfig::default()),
+            Message::Insert(_, key, value, None) if key == "foo" && value == "a=b"
+        ));
+        assert!(matches!(
+            parse_message(addr, "=a", &UnusualConfig::default()),
+            Message::Insert(_, key, value, None) if key.is_empty() && value == "a"
+        ));
+        assert!(matches!(
+            parse_message(addr, "foo", &UnusualConfig::default()),
+            Message::Retrieve(_, key) if key == "foo"
+        ));
+        assert!(matches!(
+            parse_message(addr, "foo bar?", &UnusualConfig::default()),
+            Message::Retrieve(_, key) if key == "foo bar?"
+        ));
+    }
+
+    #[test]
+    fn ttl_keys_are_only_parsed_when_enabled() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let ttl = UnusualConfig {
+            ttl: true,
+            ..UnusualConfig::default()
+        };
+
+        assert!(matches!(
+            parse_message(addr, "foo?30=bar", &UnusualConfig::default()),
+            Message::Insert(_, key, value, None) if key == "foo?30" && value == "bar"
+        ));
+        assert!(matches!(
+            parse_message(addr, "foo?30=bar", &ttl),
+            Message::Insert(_, key, value, Some(ttl))
+                if key == "foo" && value == "bar" && ttl == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            parse_message(addr, "foo?bar=baz", &ttl),
+            Message::Insert(_, key, _, None) if key == "foo?bar"
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_corrupted_log_is_returned_as_an_error() {
+        let path = env::temp_dir().join(format!("unusual-corrupted-{}.log", std::process::id()));
+        std::fs::write(&path, "not a record").unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = UnusualConfig {
+            log_path: Some(path.clone()),
+            ..UnusualConfig::default()
+        };
+        let result = run_unusual(config, socket).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let Err(e) = result else {
+            panic!("started despite the corrupted log");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn replays_spec_session() {
+        replay(include_str!("../fixtures/unusual/session.txt")).await;
+    }
+
+    #[test]
+    fn nul_prefixed_keys_are_only_deletes_when_enabled() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let delete = UnusualConfig {
+            delete: true,
+            ..UnusualConfig::default()
+        };
+
+        assert!(matches!(
+            parse_message(addr, "\0foo", &UnusualConfig::default()),
+            Message::Retrieve(_, key) if key == "\0foo"
+        ));
+        assert!(matches!(
+            parse_message(addr, "\0foo", &delete),
+            Message::Delete(_, key) if key == "foo"
+        ));
+    }
+
+    #[test]
+    fn newlines_only_split_retrieves_when_enabled() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let multi_get = UnusualConfig {
+            multi_get: true,
+            ..UnusualConfig::default()
+        };
+
+        assert!(matches!(
+            parse_message(addr, "a\nb", &UnusualConfig::default()),
+            Message::Retrieve(_, key) if key == "a\nb"
+        ));
+        assert!(matches!(
+            parse_message(addr, "a\nb", &multi_get),
+            Message::RetrieveMany(_, keys) if keys == ["a", "b"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_dump_key_is_an_ordinary_key_unless_enabled() {
+        replay(&["> __dump__=x", "> __dump__", "< __dump__=x"].join("\n")).await;
+    }
+
+    #[tokio::test]
+    async fn short_datagrams_do_not_pick_up_earlier_bytes() {
+        replay(
+            &[
+                "> longerkey=longervalue",
+                "> k=v",
+                "> k",
+                "< k=v",
+                "> longerkey",
+                "< longerkey=longervalue",
+            ]
+            .join("\n"),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn datagrams_over_the_limit_are_dropped() {
+        let too_long = format!("big={}", "x".repeat(MAX_PACKET_SIZE - 3));
+        let just_fits = format!("fits={}", "x".repeat(MAX_PACKET_SIZE - 5));
+
+        // Were `big` stored, its reply would come before the one for `fits`
+        replay(
+            &[
+                format!("> {too_long}"),
+                format!("> {just_fits}"),
+                String::from("> big"),
+                String::from("> fits"),
+                format!("< {just_fits}"),
+            ]
+            .join("\n"),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn the_key_count_follows_inserts_removals_and_evictions() {
+        let store = Store::new();
+        let len = |store: &Store| store.len.load(Ordering::Relaxed);
+
+        assert!(store.insert("a".into(), "1".into(), None, None).await);
+        assert!(store.insert("a".into(), "2".into(), None, None).await);
+        assert!(store.insert("b".into(), "1".into(), None, None).await);
+        assert_eq!(len(&store), 2);
+
+        store.remove("a").await;
+        store.remove("missing").await;
+        assert_eq!(len(&store), 1);
+
+        let expired = Some(Instant::now());
+        assert!(store.insert("c".into(), "1".into(), expired, None).await);
+        assert!(store.insert("d".into(), "1".into(), expired, None).await);
+        assert_eq!(len(&store), 3);
+        assert_eq!(store.get("c").await, None);
+        assert_eq!(len(&store), 2);
+        assert_eq!(store.evict_expired().await, 1);
+        assert_eq!(len(&store), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_new_keys_never_overfill_the_store() {
+        let store = Arc::new(Store::new());
+        let inserts = (0..200).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                store
+                    .insert(format!("key{i}"), "v".into(), None, Some(50))
+                    .await
+            })
+        });
+
+        let mut stored = 0;
+        for insert in inserts.collect::<Vec<_>>() {
+            stored += usize::from(insert.await.unwrap());
+        }
+        assert_eq!(stored, 50);
+        assert_eq!(store.len.load(Ordering::Relaxed), 50);
+        let entries = store.entries().await;
+        assert_eq!(entries.len(), 50);
+
+        // Overwrites still go through once full
+        let (key, _) = entries.into_iter().next().unwrap();
+        assert!(store.insert(key, "w".into(), None, Some(50)).await);
+        assert_eq!(store.len.load(Ordering::Relaxed), 50);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn multi_key_requests_see_the_writes_sent_before_them() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = UnusualConfig {
+            dump: true,
+            multi_get: true,
+            ..UnusualConfig::default()
+        };
+        let (addr, _) = run_unusual(config, socket).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+
+        let mut buf = [0u8; RECV_BUFFER_SIZE];
+        for i in 0..50 {
+            client.send(format!("k{i}={i}").as_bytes()).await.unwrap();
+            client.send(format!("k{i}\nzz").as_bytes()).await.unwrap();
+            let n = tokio::time::timeout(RECV_TIMEOUT, client.recv(&mut buf))
+                .await
+                .unwrap_or_else(|_| panic!("no reply to the multi-get of k{i}"))
+                .unwrap();
+            assert_eq!(
+                std::str::from_utf8(&buf[..n]).unwrap(),
+                format!("k{i}={i}\n")
+            );
+        }
+
+        client.send(b"y=2").await.unwrap();
+        client.send(DUMP_KEY.as_bytes()).await.unwrap();
+        let n = tokio::time::timeout(RECV_TIMEOUT, client.recv(&mut buf))
+            .await
+            .expect("no reply to the dump")
+            .unwrap();
+        let dump = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(dump.lines().any(|line| line == "y=2"), "{dump:?}");
+    }
+
+    // Goes through the routing and the workers without the socket, run with `--ignored` and compare
+    // the time the test takes
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "benchmark"]
+    async fn benchmark_inserts_through_the_workers() {
+        const INSERTS: usize = 100_000;
+
+        let store = Arc::new(Store::new());
+        let shared = Arc::new(Shared {
+            socket: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            store: store.clone(),
+            log: None,
+            audit: None,
+            version_reply: String::new(),
+            config: UnusualConfig::default(),
+        });
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let (tx, rx) = unbounded_channel();
+        let server = tokio::spawn(run_server(rx, shared));
+        for i in 0..INSERTS {
+            tx.send(Message::Insert(
+                addr,
+                format!("key{i}"),
+                i.to_string(),
+                None,
+            ))
+            .unwrap();
+        }
+        drop(tx);
+        server.await.unwrap();
+
+        assert_eq!(store.len.load(Ordering::Relaxed), INSERTS);
+        assert_eq!(store.get("key4242").await.as_deref(), Some("4242"));
+    }
 }
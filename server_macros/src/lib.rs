@@ -1,156 +1,871 @@
-use core::panic;
+use std::collections::HashMap;
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{Data, DeriveInput, Expr, Fields, Lit, Type, parse_macro_input};
+use syn::{Data, DataEnum, DeriveInput, Expr, Fields, Ident, Lit, Type, parse_macro_input};
 
-#[proc_macro_derive(Packet, attributes(opcode))]
+// `Packet` is the reading half, it needs an opcode and emits `OPCODE` and `deserialize`.
+// `Serialize` is the writing half, it emits `serialize_body` with just the fields and `serialize`
+// with the opcode in front. For structs the opcode is optional, without one the two are the same.
+#[derive(Clone, Copy, PartialEq)]
+enum Derive {
+    Packet,
+    Serialize,
+}
+
+#[proc_macro_derive(Packet, attributes(opcode, length, max_len, endian, count_for))]
 pub fn derive_packet(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as DeriveInput);
-    let name = input.ident;
+    derive(parse_macro_input!(input as DeriveInput), Derive::Packet)
+}
+
+#[proc_macro_derive(Serialize, attributes(opcode, length, max_len, endian, count_for))]
+pub fn derive_serialize(input: TokenStream) -> TokenStream {
+    derive(parse_macro_input!(input as DeriveInput), Derive::Serialize)
+}
+
+fn derive(input: DeriveInput, derive: Derive) -> TokenStream {
+    let name = &input.ident;
+
+    let expanded = match &input.data {
+        Data::Struct(data_struct) => derive_struct(name, &input.attrs, &data_struct.fields, derive),
+        Data::Enum(data_enum) => derive_enum(name, &input.attrs, data_enum, derive),
+        Data::Union(_) => Err(syn::Error::new_spanned(
+            name,
+            "Packet can not be derived for unions",
+        )),
+    };
+
+    TokenStream::from(expanded.unwrap_or_else(syn::Error::into_compile_error))
+}
+
+fn derive_struct(
+    name: &Ident,
+    attrs: &[syn::Attribute],
+    fields: &Fields,
+    derive: Derive,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let opcode = parse_opcode(attrs)?;
+    let endian = parse_endian(attrs)?.unwrap_or(Endian::Big);
+    let to_bytes = endian.encode_fn();
+    let FieldsCodegen {
+        deserializers,
+        serializers,
+        field_inits,
+    } = fields_codegen(fields, endian)?;
+
+    if derive == Derive::Serialize {
+        let buffer = match opcode {
+            Some(opcode) => {
+                let opcode = opcode.literal();
+                quote! { #opcode.#to_bytes().to_vec() }
+            }
+            None => quote! { Vec::new() },
+        };
+
+        return Ok(quote! {
+            impl Serialize for #name {
+                fn serialize_body(&self) -> Vec<u8> {
+                    let Self { #(#field_inits),* } = self;
+                    let mut buffer = Vec::new();
+                    #(#serializers)*
+                    buffer
+                }
+
+                fn serialize(&self) -> Vec<u8> {
+                    let mut buffer = #buffer;
+                    buffer.extend_from_slice(&self.serialize_body());
+                    buffer
+                }
+            }
+        });
+    }
+
+    let Some(opcode) = opcode else {
+        return Err(syn::Error::new_spanned(name, "Expected #[opcode = N]"));
+    };
+    let opcode_ty = opcode.ty();
+    let opcode = opcode.literal();
+
+    Ok(quote! {
+        impl Packet for #name {
+            const OPCODE: #opcode_ty = #opcode;
+
+            async fn deserialize<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Self, std::io::Error> {
+                #(#deserializers)*
+                Ok(Self {
+                    #(#field_inits),*
+                })
+            }
+        }
+    })
+}
+
+// Every variant carries its own opcode. Variants wrapping a single type delegate to its `Packet`
+// or `Serialize` impl, variants with named fields are handled inline.
+fn derive_enum(
+    name: &Ident,
+    attrs: &[syn::Attribute],
+    data_enum: &DataEnum,
+    derive: Derive,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let endian = parse_endian(attrs)?.unwrap_or(Endian::Big);
+    let to_bytes = endian.encode_fn();
+    let mut seen_opcodes: HashMap<u16, &Ident> = HashMap::new();
+    let mut width = None;
+    let mut deserialize_arms = Vec::new();
+    let mut serialize_arms = Vec::new();
+    let mut opcode_arms = Vec::new();
+    let mut opcode_checks = Vec::new();
+
+    for variant in &data_enum.variants {
+        let variant_name = &variant.ident;
+        let Some(opcode) = parse_opcode(&variant.attrs)? else {
+            return Err(syn::Error::new_spanned(variant, "Expected #[opcode = N]"));
+        };
+
+        if *width.get_or_insert(opcode.width) != opcode.width {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "Every variant must use the same opcode width",
+            ));
+        }
+        if let Some(other) = seen_opcodes.insert(opcode.value, variant_name) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                format!(
+                    "opcode {:#04x} is already used by variant `{other}`",
+                    opcode.value
+                ),
+            ));
+        }
+        let opcode = opcode.literal();
+
+        match &variant.fields {
+            Fields::Unnamed(fields_unnamed) if fields_unnamed.unnamed.len() == 1 => {
+                let inner_ty = &fields_unnamed.unnamed[0].ty;
+                deserialize_arms.push(quote! {
+                    #opcode => Ok(Self::#variant_name(<#inner_ty as Packet>::deserialize(reader).await?)),
+                });
+                serialize_arms.push(quote! {
+                    Self::#variant_name(inner) => inner.serialize_body(),
+                });
+                opcode_arms.push(quote! {
+                    Self::#variant_name(_) => #opcode,
+                });
+                opcode_checks.push(quote! {
+                    const _: () = assert!(
+                        <#inner_ty as Packet>::OPCODE == #opcode,
+                        concat!("opcode of ", stringify!(#variant_name), " does not match ", stringify!(#inner_ty))
+                    );
+                });
+            }
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "Expected a single packet or named fields",
+                ));
+            }
+            fields => {
+                let FieldsCodegen {
+                    deserializers,
+                    serializers,
+                    field_inits,
+                } = fields_codegen(fields, parse_endian(&variant.attrs)?.unwrap_or(endian))?;
+                deserialize_arms.push(quote! {
+                    #opcode => {
+                        #(#deserializers)*
+                        Ok(Self::#variant_name {
+                            #(#field_inits),*
+                        })
+                    }
+                });
+                serialize_arms.push(quote! {
+                    Self::#variant_name { #(#field_inits),* } => {
+                        let mut buffer = Vec::new();
+                        #(#serializers)*
+                        buffer
+                    }
+                });
+                opcode_arms.push(quote! {
+                    Self::#variant_name { .. } => #opcode,
+                });
+            }
+        }
+    }
+
+    if derive == Derive::Serialize {
+        // The variant's opcode goes first, whatever opcode a wrapped type may have of its own
+        return Ok(quote! {
+            impl Serialize for #name {
+                fn serialize_body(&self) -> Vec<u8> {
+                    match self {
+                        #(#serialize_arms)*
+                    }
+                }
+
+                fn serialize(&self) -> Vec<u8> {
+                    let opcode = match self {
+                        #(#opcode_arms)*
+                    };
+                    let mut buffer = opcode.#to_bytes().to_vec();
+                    buffer.extend_from_slice(&self.serialize_body());
+                    buffer
+                }
+            }
+        });
+    }
+
+    let width = width.unwrap_or(OpcodeWidth::U8);
+    let opcode_ty = width.ty();
+    let read_opcode = width.read(endian);
+    let truncated = format!("packet {{opcode:{}}} was truncated", width.hex_format());
+    let unknown = format!("unknown opcode {{opcode:{}}}", width.hex_format());
+
+    Ok(quote! {
+        #(#opcode_checks)*
+
+        impl #name {
+            // Running out of bytes before the opcode is a closed connection, anywhere after it the
+            // packet was cut short and the stream can't be trusted anymore
+            async fn deserialize<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Self, std::io::Error> {
+                let opcode = #read_opcode;
+                Self::deserialize_body(opcode, reader).await.map_err(|e| {
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(#truncated),
+                        )
+                    } else {
+                        e
+                    }
+                })
+            }
+
+            async fn deserialize_body<R: tokio::io::AsyncRead + Unpin>(opcode: #opcode_ty, reader: &mut R) -> Result<Self, std::io::Error> {
+                match opcode {
+                    #(#deserialize_arms)*
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(#unknown),
+                    )),
+                }
+            }
+        }
+    })
+}
+
+// Big-endian unless `#[endian = "le"]` is set on the type, a variant or a single field
+#[derive(Clone, Copy)]
+enum Endian {
+    Big,
+    Little,
+}
+
+impl Endian {
+    fn decode_fn(self) -> Ident {
+        match self {
+            Endian::Big => quote::format_ident!("from_be_bytes"),
+            Endian::Little => quote::format_ident!("from_le_bytes"),
+        }
+    }
+
+    fn encode_fn(self) -> Ident {
+        match self {
+            Endian::Big => quote::format_ident!("to_be_bytes"),
+            Endian::Little => quote::format_ident!("to_le_bytes"),
+        }
+    }
+}
+
+fn parse_endian(attrs: &[syn::Attribute]) -> syn::Result<Option<Endian>> {
+    let mut endian = None;
+
+    for attr in attrs {
+        if attr.path().is_ident("endian") {
+            match &attr.meta {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    value:
+                        Expr::Lit(syn::ExprLit {
+                            lit: Lit::Str(val), ..
+                        }),
+                    ..
+                }) if val.value() == "be" || val.value() == "le" => {
+                    endian = Some(if val.value() == "le" {
+                        Endian::Little
+                    } else {
+                        Endian::Big
+                    });
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "Expected #[endian = \"be\"] or #[endian = \"le\"]",
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(endian)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OpcodeWidth {
+    U8,
+    U16,
+}
+
+impl OpcodeWidth {
+    fn ty(self) -> proc_macro2::TokenStream {
+        match self {
+            OpcodeWidth::U8 => quote! { u8 },
+            OpcodeWidth::U16 => quote! { u16 },
+        }
+    }
+
+    fn read(self, endian: Endian) -> proc_macro2::TokenStream {
+        match (self, endian) {
+            (OpcodeWidth::U8, _) => quote! { reader.read_u8().await? },
+            (OpcodeWidth::U16, Endian::Big) => quote! { reader.read_u16().await? },
+            (OpcodeWidth::U16, Endian::Little) => quote! { reader.read_u16_le().await? },
+        }
+    }
+
+    fn hex_format(self) -> &'static str {
+        match self {
+            OpcodeWidth::U8 => "#04x",
+            OpcodeWidth::U16 => "#06x",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Opcode {
+    value: u16,
+    width: OpcodeWidth,
+}
+
+impl Opcode {
+    fn ty(self) -> proc_macro2::TokenStream {
+        self.width.ty()
+    }
+
+    // Suffixed, so it has the opcode's type wherever it is used
+    fn literal(self) -> proc_macro2::Literal {
+        match self.width {
+            OpcodeWidth::U8 => proc_macro2::Literal::u8_suffixed(self.value as u8),
+            OpcodeWidth::U16 => proc_macro2::Literal::u16_suffixed(self.value),
+        }
+    }
+}
+
+// `#[opcode = N]` is a single byte, `#[opcode(u8, N)]` or `#[opcode(u16, N)]` pick the width
+fn parse_opcode(attrs: &[syn::Attribute]) -> syn::Result<Option<Opcode>> {
     let mut opcode = None;
 
-    for attr in &input.attrs {
+    for attr in attrs {
         if attr.path().is_ident("opcode") {
             match &attr.meta {
-                syn::Meta::NameValue(meta) => {
-                    match &meta.value {
-                        Expr::Lit(lit) => match &lit.lit {
-                            Lit::Int(val) => {
-                                opcode = Some(val.base10_parse::<u8>().unwrap());
-                            }
-                            _ => {
-                                panic!("Expected #[opcode = N]")
-                            }
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    value:
+                        Expr::Lit(syn::ExprLit {
+                            lit: Lit::Int(val), ..
+                        }),
+                    ..
+                }) => {
+                    opcode = Some(Opcode {
+                        value: val.base10_parse::<u8>()?.into(),
+                        width: OpcodeWidth::U8,
+                    });
+                }
+                syn::Meta::List(_) => {
+                    let (width, val) = attr.parse_args_with(|input: syn::parse::ParseStream| {
+                        let width: Ident = input.parse()?;
+                        input.parse::<syn::Token![,]>()?;
+                        let val: syn::LitInt = input.parse()?;
+                        Ok((width, val))
+                    })?;
+                    opcode = Some(match width.to_string().as_str() {
+                        "u8" => Opcode {
+                            value: val.base10_parse::<u8>()?.into(),
+                            width: OpcodeWidth::U8,
+                        },
+                        "u16" => Opcode {
+                            value: val.base10_parse::<u16>()?,
+                            width: OpcodeWidth::U16,
                         },
                         _ => {
-                            panic!("Expected #[opcode = N]")
+                            return Err(syn::Error::new_spanned(
+                                width,
+                                "Opcodes can only be u8 or u16",
+                            ));
                         }
-                    };
+                    });
                 }
                 _ => {
-                    panic!("Expected #[opcode = N]")
+                    return Err(syn::Error::new_spanned(attr, "Expected #[opcode = N]"));
                 }
             }
         }
     }
 
-    // Deserialization logic
+    Ok(opcode)
+}
+
+struct FieldsCodegen {
+    deserializers: Vec<proc_macro2::TokenStream>,
+    serializers: Vec<proc_macro2::TokenStream>,
+    field_inits: Vec<proc_macro2::TokenStream>,
+}
+
+// Deserializers bind each field to a local of the same name, serializers expect each field
+// to be bound by reference under its own name.
+fn fields_codegen(fields: &Fields, endian: Endian) -> syn::Result<FieldsCodegen> {
     let mut deserializers = Vec::new();
+    let mut serializers = Vec::new();
     let mut field_inits = Vec::new();
 
-    if let Data::Struct(data_struct) = &input.data {
-        if let Fields::Named(fields_named) = &data_struct.fields {
-            for field in &fields_named.named {
-                let field_name = field.ident.as_ref().unwrap();
-                let ty = &field.ty;
-
-                if let Some(ty_str) = type_ident_string(ty) {
-                    match ty_str.as_str() {
-                        "u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64" => {
-                            if let Some(size) = int_byte_size(&ty_str) {
-                                let buf_ident = syn::Ident::new(
-                                    &format!("buf_{}", field_name),
-                                    field_name.span(),
-                                );
-                                deserializers.push(quote! {
-                                    let mut #buf_ident = [0u8; #size];
-                                    reader.read_exact(&mut #buf_ident).await?;
-                                    let #field_name = <#ty>::from_be_bytes(#buf_ident);
-                                });
-                                field_inits.push(quote! { #field_name });
-                            }
+    let fields_named = match fields {
+        Fields::Named(fields_named) => &fields_named.named,
+        Fields::Unit => {
+            return Ok(FieldsCodegen {
+                deserializers,
+                serializers,
+                field_inits,
+            });
+        }
+        Fields::Unnamed(_) => {
+            return Err(syn::Error::new_spanned(
+                fields,
+                "Packet fields must be named",
+            ));
+        }
+    };
+
+    // A `#[count_for(items)]` field holds the length of `items`, which then has no prefix of its own
+    let mut counts: HashMap<String, &Ident> = HashMap::new();
+    for field in fields_named {
+        let Some(target) = parse_count_for(field)? else {
+            continue;
+        };
+        if !fields_named
+            .iter()
+            .any(|other| other.ident.as_ref() == Some(&target))
+        {
+            return Err(syn::Error::new_spanned(
+                &target,
+                format!("There is no field `{target}` to count"),
+            ));
+        }
+        if counts
+            .insert(target.to_string(), field.ident.as_ref().unwrap())
+            .is_some()
+        {
+            return Err(syn::Error::new_spanned(
+                &target,
+                format!("`{target}` is already counted by another field"),
+            ));
+        }
+    }
+
+    let mut seen = Vec::new();
+    for field in fields_named {
+        let field_name = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let count_for = parse_count_for(field)?;
+        let count = counts.get(&field_name.to_string()).copied();
+        if let Some(count) = count
+            && !seen.contains(&count)
+        {
+            return Err(syn::Error::new_spanned(
+                field,
+                format!("`{count}` must come before the field it counts"),
+            ));
+        }
+        seen.push(field_name);
+        let length_prefix = LengthPrefix::parse(field, count)?;
+        let endian = parse_endian(&field.attrs)?.unwrap_or(endian);
+        let (from_bytes, to_bytes) = (endian.decode_fn(), endian.encode_fn());
+
+        if let Some((inner_ty, len)) = extract_array_type(ty) {
+            let Some(size) = type_ident_string(&inner_ty)
+                .as_deref()
+                .and_then(numeric_byte_size)
+            else {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Arrays must have a supported numeric item type",
+                ));
+            };
+            let buf_ident = syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+
+            deserializers.push(quote! {
+                let mut #buf_ident = [0u8; #size * (#len)];
+                reader.read_exact(&mut #buf_ident).await?;
+                let #field_name: [#inner_ty; #len] = std::array::from_fn(|i| {
+                    <#inner_ty>::#from_bytes(#buf_ident[i * #size..(i + 1) * #size].try_into().unwrap())
+                });
+            });
+            serializers.push(quote! {
+                for item in #field_name {
+                    buffer.extend_from_slice(&item.#to_bytes());
+                }
+            });
+            field_inits.push(quote! { #field_name });
+            continue;
+        }
+
+        let Some(ty_str) = type_ident_string(ty) else {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "This type is not parsable for a packet",
+            ));
+        };
+
+        if count_for.is_some() && !matches!(ty_str.as_str(), "u8" | "u16" | "u32" | "u64") {
+            return Err(syn::Error::new_spanned(
+                field,
+                "#[count_for] needs an unsigned integer field",
+            ));
+        }
+
+        match ty_str.as_str() {
+            "u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64" | "f32" | "f64" => {
+                if let Some(size) = numeric_byte_size(&ty_str) {
+                    let buf_ident =
+                        syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+                    deserializers.push(quote! {
+                        let mut #buf_ident = [0u8; #size];
+                        reader.read_exact(&mut #buf_ident).await?;
+                        let #field_name = <#ty>::#from_bytes(#buf_ident);
+                    });
+                    // A count is written from what it counts, so the two can't disagree on the wire
+                    serializers.push(match &count_for {
+                        Some(target) => quote! {
+                            let _ = #field_name;
+                            buffer.extend_from_slice(
+                                &<#ty>::try_from(#target.len())
+                                    .expect(concat!(stringify!(#target), " is too long for ", stringify!(#field_name)))
+                                    .#to_bytes(),
+                            );
+                        },
+                        None => quote! {
+                            buffer.extend_from_slice(&#field_name.#to_bytes());
+                        },
+                    });
+                    field_inits.push(quote! { #field_name });
+                }
+            }
+            "bool" => {
+                let buf_ident = syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+                deserializers.push(quote! {
+                    let mut #buf_ident = [0u8; 1];
+                    reader.read_exact(&mut #buf_ident).await?;
+                    let #field_name = match #buf_ident[0] {
+                        0 => false,
+                        1 => true,
+                        value => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                format!(concat!("invalid bool {} for ", stringify!(#field_name)), value),
+                            ));
                         }
-                        "Vec" => {
-                            if let Some(inner_ty) = extract_vec_inner_type(ty) {
-                                if let Some(inner_ty_str) = type_ident_string(&inner_ty) {
-                                    if let Some(size) = int_byte_size(&inner_ty_str) {
-                                        let len_ident = syn::Ident::new(
-                                            &format!("len_{}", field_name),
-                                            field_name.span(),
-                                        );
-                                        let buf_ident = syn::Ident::new(
-                                            &format!("buf_{}", field_name),
-                                            field_name.span(),
-                                        );
-                                        let items_ident = syn::Ident::new(
-                                            &format!("items_{}", field_name),
-                                            field_name.span(),
-                                        );
-
-                                        deserializers.push(quote! {
-                                            let mut #len_ident = [0u8; 1];
-                                            reader.read_exact(&mut #len_ident).await?;
-                                            let len = #len_ident[0] as usize;
-
-                                            let mut #buf_ident = vec![0u8; len * #size];
-                                            reader.read_exact(&mut #buf_ident).await?;
-
-                                            let mut #items_ident = Vec::with_capacity(len);
-                                            for chunk in #buf_ident.chunks_exact(#size) {
-                                                let item = <#inner_ty>::from_be_bytes(chunk.try_into().unwrap());
-                                                #items_ident.push(item);
-                                            }
-
-                                            let #field_name = #items_ident;
-                                        });
-                                        field_inits.push(quote! { #field_name });
-                                    } else {
-                                        panic!(
-                                            "Vec<{}> is not a supported integer type",
-                                            inner_ty_str
-                                        );
-                                    }
-                                }
+                    };
+                });
+                serializers.push(quote! {
+                    buffer.push(u8::from(*#field_name));
+                });
+                field_inits.push(quote! { #field_name });
+            }
+            "Vec" => {
+                if let Some(inner_ty) = extract_vec_inner_type(ty)
+                    && let Some(inner_ty_str) = type_ident_string(&inner_ty)
+                {
+                    if let Some(size) = numeric_byte_size(&inner_ty_str) {
+                        let read_len = length_prefix.read(field_name, endian);
+                        let write_len = length_prefix.write(field_name, endian);
+                        let buf_ident =
+                            syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+                        let items_ident =
+                            syn::Ident::new(&format!("items_{}", field_name), field_name.span());
+
+                        deserializers.push(quote! {
+                            #read_len
+
+                            let Some(byte_len) = len.checked_mul(#size) else {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!(concat!(stringify!(#field_name), " length {} is too large"), len),
+                                ));
+                            };
+                            let mut #buf_ident = vec![0u8; byte_len];
+                            reader.read_exact(&mut #buf_ident).await?;
+
+                            let mut #items_ident = Vec::with_capacity(len);
+                            for chunk in #buf_ident.chunks_exact(#size) {
+                                let bytes = chunk.try_into().map_err(|_| {
+                                    std::io::Error::new(
+                                        std::io::ErrorKind::InvalidData,
+                                        concat!(stringify!(#field_name), " has a truncated element"),
+                                    )
+                                })?;
+                                #items_ident.push(<#inner_ty>::#from_bytes(bytes));
                             }
-                        }
-                        "String" => {
-                            let len_ident =
-                                syn::Ident::new(&format!("len_{}", field_name), field_name.span());
-                            let buf_ident =
-                                syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
-
-                            // Deserialize
-                            deserializers.push(quote! {
-                                let mut #len_ident = [0u8; 1];
-                                reader.read_exact(&mut #len_ident).await?;
-                                let len = #len_ident[0] as usize;
-                                let mut #buf_ident = vec![0u8; len];
-                                reader.read_exact(&mut #buf_ident).await?;
-                                let #field_name = String::from_utf8(#buf_ident)
-                                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-                            });
-                            field_inits.push(quote! { #field_name });
-                        }
-                        _ => {
-                            panic!("This type {ty_str} is not parsable for a packet")
-                        }
+
+                            let #field_name = #items_ident;
+                        });
+                        serializers.push(quote! {
+                            #write_len
+                            for item in #field_name {
+                                buffer.extend_from_slice(&item.#to_bytes());
+                            }
+                        });
+                        field_inits.push(quote! { #field_name });
+                    } else {
+                        // Anything else is a nested record that derives `Packet` and `Serialize`
+                        // itself, only its fields are on the wire
+                        let read_len = length_prefix.read(field_name, endian);
+                        let write_len = length_prefix.write(field_name, endian);
+                        let items_ident =
+                            syn::Ident::new(&format!("items_{}", field_name), field_name.span());
+
+                        deserializers.push(quote! {
+                            #read_len
+
+                            let mut #items_ident = Vec::new();
+                            for _ in 0..len {
+                                #items_ident.push(<#inner_ty as Packet>::deserialize(reader).await?);
+                            }
+
+                            let #field_name = #items_ident;
+                        });
+                        serializers.push(quote! {
+                            #write_len
+                            for item in #field_name {
+                                buffer.extend_from_slice(&item.serialize_body());
+                            }
+                        });
+                        field_inits.push(quote! { #field_name });
                     }
+                } else {
+                    return Err(syn::Error::new_spanned(ty, "Vec must have an item type"));
                 }
             }
+            // A presence byte, then the value when it is non-zero
+            "Option" => {
+                let Some(inner_ty) = extract_option_inner_type(ty) else {
+                    return Err(syn::Error::new_spanned(ty, "Option must have an item type"));
+                };
+                let inner_ty_str = type_ident_string(&inner_ty).unwrap_or_default();
+                let flag_ident =
+                    syn::Ident::new(&format!("flag_{}", field_name), field_name.span());
+                let buf_ident = syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+
+                let (read_value, write_value) = if let Some(size) = numeric_byte_size(&inner_ty_str)
+                {
+                    (
+                        quote! {
+                            let mut #buf_ident = [0u8; #size];
+                            reader.read_exact(&mut #buf_ident).await?;
+                            <#inner_ty>::#from_bytes(#buf_ident)
+                        },
+                        quote! {
+                            buffer.extend_from_slice(&#field_name.#to_bytes());
+                        },
+                    )
+                } else if inner_ty_str == "String" {
+                    let read_len = length_prefix.read(field_name, endian);
+                    let write_len = length_prefix.write(field_name, endian);
+                    (
+                        quote! {
+                            #read_len
+                            let mut #buf_ident = vec![0u8; len];
+                            reader.read_exact(&mut #buf_ident).await?;
+                            String::from_utf8(#buf_ident)
+                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+                        },
+                        quote! {
+                            #write_len
+                            buffer.extend_from_slice(#field_name.as_bytes());
+                        },
+                    )
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        field,
+                        format!("Option<{inner_ty_str}> is not a supported item type"),
+                    ));
+                };
+
+                deserializers.push(quote! {
+                    let mut #flag_ident = [0u8; 1];
+                    reader.read_exact(&mut #flag_ident).await?;
+                    let #field_name = if #flag_ident[0] != 0 {
+                        Some({ #read_value })
+                    } else {
+                        None
+                    };
+                });
+                serializers.push(quote! {
+                    match #field_name {
+                        Some(#field_name) => {
+                            buffer.push(1);
+                            #write_value
+                        }
+                        None => buffer.push(0),
+                    }
+                });
+                field_inits.push(quote! { #field_name });
+            }
+            "String" => {
+                let read_len = length_prefix.read(field_name, endian);
+                let write_len = length_prefix.write(field_name, endian);
+                let buf_ident = syn::Ident::new(&format!("buf_{}", field_name), field_name.span());
+
+                deserializers.push(quote! {
+                    #read_len
+                    let mut #buf_ident = vec![0u8; len];
+                    reader.read_exact(&mut #buf_ident).await?;
+                    let #field_name = String::from_utf8(#buf_ident)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                });
+                serializers.push(quote! {
+                    #write_len
+                    buffer.extend_from_slice(#field_name.as_bytes());
+                });
+                field_inits.push(quote! { #field_name });
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    format!("This type {ty_str} is not parsable for a packet"),
+                ));
+            }
         }
     }
 
-    let expanded = quote! {
-        impl Packet for #name {
-            const OPCODE: u8 = #opcode;
+    Ok(FieldsCodegen {
+        deserializers,
+        serializers,
+        field_inits,
+    })
+}
+
+// The length prefix in front of `String` and `Vec` fields, `u8` unless overridden, or the
+// earlier field that holds the length instead
+struct LengthPrefix {
+    ty: Type,
+    max_len: Option<usize>,
+    count: Option<Ident>,
+}
 
-            // fn serialize(&self) -> Vec<u8> {
-            //     let mut buffer = Vec::new();
-            //     #(#serialize_fields)*
-            //     buffer
-            // }
+impl LengthPrefix {
+    fn parse(field: &syn::Field, count: Option<&Ident>) -> syn::Result<Self> {
+        let mut ty = syn::parse_quote!(u8);
+        let mut max_len = None;
 
-            async fn deserialize<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Self, std::io::Error> {
-                #(#deserializers)*
-                Ok(Self {
-                    #(#field_inits),*
-                })
+        for attr in &field.attrs {
+            if attr.path().is_ident("length") {
+                match attr.parse_args::<Type>() {
+                    Ok(len_ty)
+                        if matches!(
+                            type_ident_string(&len_ty).as_deref(),
+                            Some("u8" | "u16" | "u32")
+                        ) =>
+                    {
+                        ty = len_ty;
+                    }
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            attr,
+                            "Expected #[length(u8|u16|u32)]",
+                        ));
+                    }
+                }
+            } else if attr.path().is_ident("max_len") {
+                match &attr.meta {
+                    syn::Meta::NameValue(syn::MetaNameValue {
+                        value:
+                            Expr::Lit(syn::ExprLit {
+                                lit: Lit::Int(val), ..
+                            }),
+                        ..
+                    }) => {
+                        max_len = Some(val.base10_parse::<usize>()?);
+                    }
+                    _ => {
+                        return Err(syn::Error::new_spanned(attr, "Expected #[max_len = N]"));
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            ty,
+            max_len,
+            count: count.cloned(),
+        })
+    }
+
+    // Binds the decoded length to `len`, rejecting it before anything gets allocated for it
+    fn read(&self, field_name: &Ident, endian: Endian) -> proc_macro2::TokenStream {
+        let ty = &self.ty;
+        let from_bytes = endian.decode_fn();
+        let len_ident = syn::Ident::new(&format!("len_{}", field_name), field_name.span());
+        let max_len_check = self.max_len.map(|max_len| {
+            quote! {
+                if len > #max_len {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(concat!(stringify!(#field_name), " length {} exceeds the maximum of {}"), len, #max_len),
+                    ));
+                }
             }
+        });
+
+        if let Some(count) = &self.count {
+            return quote! {
+                let len = #count as usize;
+                #max_len_check
+            };
+        }
+
+        quote! {
+            let mut #len_ident = [0u8; std::mem::size_of::<#ty>()];
+            reader.read_exact(&mut #len_ident).await?;
+            let len = <#ty>::#from_bytes(#len_ident) as usize;
+            #max_len_check
+        }
+    }
+
+    fn write(&self, field_name: &Ident, endian: Endian) -> proc_macro2::TokenStream {
+        if self.count.is_some() {
+            return quote! {};
+        }
+
+        let ty = &self.ty;
+        let to_bytes = endian.encode_fn();
+        quote! {
+            buffer.extend_from_slice(
+                &<#ty>::try_from(#field_name.len())
+                    .expect(concat!(stringify!(#field_name), " is too long for its length prefix"))
+                    .#to_bytes(),
+            );
         }
+    }
+}
+
+fn parse_count_for(field: &syn::Field) -> syn::Result<Option<Ident>> {
+    let Some(attr) = field
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("count_for"))
+    else {
+        return Ok(None);
     };
 
-    TokenStream::from(expanded)
+    attr.parse_args::<Ident>()
+        .map(Some)
+        .map_err(|_| syn::Error::new_spanned(attr, "Expected #[count_for(field)]"))
 }
 
 fn type_ident_string(ty: &Type) -> Option<String> {
@@ -161,25 +876,40 @@ fn type_ident_string(ty: &Type) -> Option<String> {
     None
 }
 
-fn int_byte_size(ty: &str) -> Option<usize> {
+fn numeric_byte_size(ty: &str) -> Option<usize> {
     match ty {
         "u8" | "i8" => Some(1),
         "u16" | "i16" => Some(2),
-        "u32" | "i32" => Some(4),
-        "u64" | "i64" => Some(8),
+        "u32" | "i32" | "f32" => Some(4),
+        "u64" | "i64" | "f64" => Some(8),
         _ => None,
     }
 }
 
+fn extract_array_type(ty: &Type) -> Option<(Type, Expr)> {
+    if let Type::Array(type_array) = ty {
+        return Some(((*type_array.elem).clone(), type_array.len.clone()));
+    }
+    None
+}
+
 fn extract_vec_inner_type(ty: &Type) -> Option<Type> {
+    extract_inner_type(ty, "Vec")
+}
+
+fn extract_option_inner_type(ty: &Type) -> Option<Type> {
+    extract_inner_type(ty, "Option")
+}
+
+// The `T` of a `wrapper<T>` type
+fn extract_inner_type(ty: &Type, wrapper: &str) -> Option<Type> {
     if let Type::Path(type_path) = ty {
         let segment = type_path.path.segments.last()?;
-        if segment.ident == "Vec" {
-            if let syn::PathArguments::AngleBracketed(args) = &segment.arguments {
-                if let Some(syn::GenericArgument::Type(inner_ty)) = args.args.first() {
-                    return Some(inner_ty.clone());
-                }
-            }
+        if segment.ident == wrapper
+            && let syn::PathArguments::AngleBracketed(args) = &segment.arguments
+            && let Some(syn::GenericArgument::Type(inner_ty)) = args.args.first()
+        {
+            return Some(inner_ty.clone());
         }
     }
     None
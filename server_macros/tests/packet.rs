@@ -0,0 +1,320 @@
+use server_macros::{Packet, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The derives implement these by name, so the tests declare them the way the server does
+trait Serialize {
+    fn serialize_body(&self) -> Vec<u8>;
+
+    fn serialize(&self) -> Vec<u8>;
+}
+
+trait Packet: Sized {
+    const OPCODE: u8;
+
+    async fn deserialize<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, std::io::Error>;
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x01]
+struct Numbers {
+    a: u8,
+    b: u16,
+    c: u32,
+    d: u64,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x02]
+struct Lists {
+    name: String,
+    roads: Vec<u16>,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x03]
+struct Reading {
+    temperature: f32,
+    pressure: f64,
+}
+
+fn numbers() -> (Numbers, Vec<u8>) {
+    let packet = Numbers {
+        a: 0x01,
+        b: 0x0203,
+        c: 0x04050607,
+        d: 0x08090a0b0c0d0e0f,
+    };
+    let bytes = vec![
+        0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+    (packet, bytes)
+}
+
+fn lists() -> (Lists, Vec<u8>) {
+    let packet = Lists {
+        name: String::from("UN1X"),
+        roads: vec![66, 368, 5000],
+    };
+    let bytes = vec![
+        0x02, 0x04, b'U', b'N', b'1', b'X', 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88,
+    ];
+    (packet, bytes)
+}
+
+#[tokio::test]
+async fn integers_round_trip() {
+    let (packet, bytes) = numbers();
+
+    assert_eq!(bytes[0], Numbers::OPCODE);
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(
+        Numbers::deserialize(&mut &bytes[1..]).await.unwrap(),
+        packet
+    );
+}
+
+#[tokio::test]
+async fn strings_and_vecs_round_trip() {
+    let (packet, bytes) = lists();
+
+    assert_eq!(bytes[0], Lists::OPCODE);
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Lists::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+}
+
+#[tokio::test]
+async fn floats_round_trip() {
+    let packet = Reading {
+        temperature: 21.5,
+        pressure: -0.25,
+    };
+    let bytes = [
+        0x03, 0x41, 0xac, 0x00, 0x00, 0xbf, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    assert_eq!(bytes[0], Reading::OPCODE);
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(
+        Reading::deserialize(&mut &bytes[1..]).await.unwrap(),
+        packet
+    );
+}
+
+#[tokio::test]
+async fn every_truncated_prefix_is_an_error() {
+    let (_, bytes) = numbers();
+    let body = &bytes[1..];
+    for len in 0..body.len() {
+        assert!(
+            Numbers::deserialize(&mut &body[..len]).await.is_err(),
+            "{len} of {} bytes decoded",
+            body.len()
+        );
+    }
+
+    let (_, bytes) = lists();
+    let body = &bytes[1..];
+    for len in 0..body.len() {
+        assert!(
+            Lists::deserialize(&mut &body[..len]).await.is_err(),
+            "{len} of {} bytes decoded",
+            body.len()
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x04]
+struct Flags {
+    on: bool,
+    off: bool,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x05]
+struct Grid {
+    cells: [u16; 3],
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x06]
+struct Wide {
+    #[length(u16)]
+    name: String,
+    #[length(u32)]
+    roads: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x07]
+struct Bounded {
+    #[max_len = 4]
+    name: String,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x08]
+struct Maybe {
+    limit: Option<u16>,
+    plate: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[endian = "le"]
+#[opcode = 0x09]
+struct Little {
+    a: u16,
+    b: u32,
+    #[endian = "be"]
+    c: u16,
+    #[length(u16)]
+    roads: Vec<u16>,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x0a]
+struct Entry {
+    mile: u16,
+    limit: u16,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+#[opcode = 0x0b]
+struct Table {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, PartialEq, Packet, Serialize)]
+enum Frame {
+    #[opcode(u16, 0x0102)]
+    Ping { id: u8 },
+    #[opcode(u16, 0x0203)]
+    Pong { id: u8 },
+}
+
+#[tokio::test]
+async fn bools_are_a_zero_or_one_byte() {
+    let packet = Flags {
+        on: true,
+        off: false,
+    };
+    let bytes = [0x04, 0x01, 0x00];
+
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Flags::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+
+    let e = Flags::deserialize(&mut &[0x02, 0x00][..])
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn arrays_have_no_length_prefix() {
+    let packet = Grid { cells: [1, 2, 3] };
+    let bytes = [0x05, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03];
+
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Grid::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+}
+
+#[tokio::test]
+async fn length_prefixes_can_be_wider() {
+    let packet = Wide {
+        name: String::from("hi"),
+        roads: vec![7, 8],
+    };
+    let bytes = [
+        0x06, 0x00, 0x02, b'h', b'i', 0x00, 0x00, 0x00, 0x02, 0x07, 0x08,
+    ];
+
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Wide::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+}
+
+#[tokio::test]
+async fn lengths_over_max_len_are_rejected() {
+    let packet = Bounded {
+        name: String::from("abcd"),
+    };
+    let bytes = [0x07, 0x04, b'a', b'b', b'c', b'd'];
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(
+        Bounded::deserialize(&mut &bytes[1..]).await.unwrap(),
+        packet
+    );
+
+    // Refused from the prefix alone, before the bytes it announces are waited for
+    let e = Bounded::deserialize(&mut &[0x05][..]).await.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn options_have_a_presence_byte() {
+    let cases = [
+        (
+            Maybe {
+                limit: Some(60),
+                plate: None,
+            },
+            vec![0x08, 0x01, 0x00, 0x3c, 0x00],
+        ),
+        (
+            Maybe {
+                limit: None,
+                plate: Some(String::from("AB")),
+            },
+            vec![0x08, 0x00, 0x01, 0x02, b'A', b'B'],
+        ),
+    ];
+
+    for (packet, bytes) in cases {
+        assert_eq!(packet.serialize(), bytes);
+        assert_eq!(Maybe::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+    }
+}
+
+#[tokio::test]
+async fn little_endian_types_can_have_big_endian_fields() {
+    let packet = Little {
+        a: 0x0102,
+        b: 0x03040506,
+        c: 0x0708,
+        roads: vec![0x0a0b],
+    };
+    let bytes = [
+        0x09, 0x02, 0x01, 0x06, 0x05, 0x04, 0x03, 0x07, 0x08, 0x01, 0x00, 0x0b, 0x0a,
+    ];
+
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Little::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+}
+
+#[tokio::test]
+async fn nested_records_are_written_without_their_opcode() {
+    let packet = Table {
+        entries: vec![Entry { mile: 1, limit: 2 }, Entry { mile: 3, limit: 4 }],
+    };
+    let bytes = [0x0b, 0x02, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04];
+
+    assert_eq!(packet.serialize(), bytes);
+    assert_eq!(Table::deserialize(&mut &bytes[1..]).await.unwrap(), packet);
+}
+
+#[tokio::test]
+async fn two_byte_opcodes_pick_the_variant() {
+    assert_eq!(Frame::Ping { id: 7 }.serialize(), [0x01, 0x02, 0x07]);
+    assert_eq!(
+        Frame::deserialize(&mut &[0x02, 0x03, 0x09][..])
+            .await
+            .unwrap(),
+        Frame::Pong { id: 9 }
+    );
+
+    // 0x0103 shares its bytes with the known opcodes but is neither of them
+    let e = Frame::deserialize(&mut &[0x01, 0x03, 0x09][..])
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}